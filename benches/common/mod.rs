@@ -0,0 +1,29 @@
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const ITERATIONS: usize = 100_000;
+
+/// Runs `f` on `threads` threads at once and returns the average time per iteration.
+pub fn contend<L, F>(threads: usize, lock: L, f: F) -> Duration
+    where L: Send + Sync + 'static, F: Fn(&L) + Send + Sync + 'static
+{
+    let shared = Arc::new((lock, f, Barrier::new(threads + 1)));
+    let handles: Vec<_> = (0..threads).map(|_| {
+        let shared = shared.clone();
+        thread::spawn(move || {
+            let (ref lock, ref f, ref barrier) = *shared;
+            barrier.wait();
+            for _ in 0..ITERATIONS {
+                f(lock);
+            }
+        })
+    }).collect();
+
+    shared.2.wait();
+    let start = Instant::now();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    start.elapsed() / (threads * ITERATIONS) as u32
+}
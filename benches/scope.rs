@@ -0,0 +1,36 @@
+//! Private vs. shared futexes under contention.
+//!
+//! Run with `cargo bench --bench scope`.
+
+extern crate futex;
+
+mod common;
+
+use std::time::Duration;
+use futex::{RawMutex, RawRwLock};
+use futex::raw::{Futex, RwFutex2, Private, Scope, Shared};
+use common::{contend, ITERATIONS};
+
+const THREADS: usize = 8;
+
+fn mutex<S: Scope>() -> Duration {
+    contend(THREADS, Futex::<S>::new(), |lock| {
+        lock.lock();
+        lock.unlock(());
+    })
+}
+
+fn rwlock<S: Scope>() -> Duration {
+    contend(THREADS, RwFutex2::<S>::new(), |lock| {
+        lock.acquire_write();
+        lock.release_write(());
+        lock.acquire_read();
+        lock.release_read(());
+    })
+}
+
+fn main() {
+    println!("{} threads, {} iterations each", THREADS, ITERATIONS);
+    println!("mutex   private: {:>8?}/iter  shared: {:>8?}/iter", mutex::<Private>(), mutex::<Shared>());
+    println!("rwlock  private: {:>8?}/iter  shared: {:>8?}/iter", rwlock::<Private>(), rwlock::<Shared>());
+}
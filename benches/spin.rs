@@ -0,0 +1,88 @@
+//! Adaptive spinning vs. going to sleep right away.
+//!
+//! Run with `cargo bench --bench spin`. For comparison, this also runs
+//! `raw::Futex` from before it could spin (see `OldFutex`).
+
+extern crate futex;
+
+mod common;
+
+use std::hint;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::Duration;
+use futex::RawMutex;
+use futex::mutex::Mutex;
+use futex::raw::{self, Private, DEFAULT_MAX_SPINS};
+use futex::sys::{futex_wait, futex_wake};
+use common::{contend, ITERATIONS};
+
+/// `raw::Futex` before it learned to spin.
+///
+/// The word counts down from 1 (unlocked) and -1 means contended. Waiters go
+/// to sleep right away and a contended release wakes up all of them.
+/// Unlike the original, waiters mark the lock as contended and check whether
+/// it's free in a single step (like its `lock_contended` did), as it could
+/// lose a wakeup otherwise and hang the bench.
+struct OldFutex {
+    futex: AtomicI32,
+}
+
+impl RawMutex for OldFutex {
+    type LockState = ();
+
+    fn lock(&self) {
+        if self.futex.fetch_sub(1, Ordering::Acquire) == 1 {
+            return;
+        }
+        while self.futex.swap(-1, Ordering::Acquire) != 1 {
+            let _ = futex_wait::<Private>(&self.futex, -1);
+        }
+    }
+
+    fn try_lock(&self) -> Option<()> {
+        self.futex.compare_exchange(1, 0, Ordering::Acquire, Ordering::Relaxed).ok().map(|_| ())
+    }
+
+    fn unlock(&self, _: ()) {
+        if self.futex.fetch_add(1, Ordering::Release) != 0 {
+            self.futex.store(1, Ordering::Release);
+            let _ = futex_wake::<Private>(&self.futex, i32::MAX);
+        }
+    }
+}
+
+/// Increments a counter under the lock, then works outside of it for a bit.
+fn mutex(threads: usize, max_spins: u32, work: usize) -> Duration {
+    counter(threads, raw::Mutex::with_max_spins(max_spins), work)
+}
+
+/// Same with `OldFutex`.
+fn old_mutex(threads: usize, work: usize) -> Duration {
+    counter(threads, OldFutex { futex: AtomicI32::new(1) }, work)
+}
+
+fn counter<L: RawMutex + Send + Sync + 'static>(threads: usize, lock: L, work: usize) -> Duration {
+    let mutex = Mutex::new(lock, 0u64);
+    contend(threads, mutex, move |mutex| {
+        {
+            let mut guard = mutex.lock();
+            for _ in 0..work {
+                *guard = hint::black_box(*guard + 1);
+            }
+        }
+        for _ in 0..work {
+            hint::black_box(());
+        }
+    })
+}
+
+fn main() {
+    println!("{} iterations per thread", ITERATIONS);
+    for &threads in &[2, 4, 8] {
+        for &work in &[1, 100] {
+            println!("{} threads, {:>3} work: old {:>8?}/iter  no spinning {:>8?}/iter  adaptive {:>8?}/iter",
+                     threads, work, old_mutex(threads, work), mutex(threads, 0, work),
+                     mutex(threads, DEFAULT_MAX_SPINS, work));
+        }
+    }
+}
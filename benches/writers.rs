@@ -0,0 +1,103 @@
+//! Writers competing for a `RwFutex2`.
+//!
+//! Run with `cargo bench --bench writers`. For comparison, this also runs the
+//! way writers used to take over the lock (see `RacingWriters`).
+
+extern crate futex;
+
+mod common;
+
+use std::hint;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::Duration;
+use futex::RawRwLock;
+use futex::raw::{PhaseFair, Policy, Private, ReaderPreferring, RwLock, WriterPreferring};
+use futex::sys::{futex_wait, futex_wake};
+use common::{contend, ITERATIONS};
+
+// any waiting writer may take the lock
+const SHOVE: i32 = 1 << 30;
+const WRITERS: i32 = SHOVE - 1;
+
+/// The write side of `RwFutex2` before releases handed the lock over.
+///
+/// A release sets the shove flag and wakes up a writer, which then races
+/// everyone else (woken up or not) to take the flag. There are no readers
+/// here as they don't change how writers get the lock.
+#[derive(Default)]
+struct RacingWriters {
+    futex: AtomicI32,
+}
+
+impl RacingWriters {
+    fn acquire_write(&self) {
+        let mut val = self.futex.fetch_add(1, Ordering::Acquire) + 1;
+        loop {
+            if val & SHOVE != 0 {
+                // whoever manages to eat the shove flag wins
+                match self.futex.compare_exchange(val, val & !SHOVE, Ordering::Acquire, Ordering::Relaxed) {
+                    Ok(_) => return,
+                    Err(x) => val = x,
+                }
+            } else if val & WRITERS == 1 {
+                // I'm the only writer
+                return;
+            } else {
+                let _ = futex_wait::<Private>(&self.futex, val);
+                val = self.futex.load(Ordering::Acquire);
+            }
+        }
+    }
+
+    fn release_write(&self) {
+        let mut val = self.futex.load(Ordering::Relaxed);
+        loop {
+            let newval = if val == 1 { 0 } else { (val - 1) | SHOVE };
+            match self.futex.compare_exchange_weak(val, newval, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) if newval == 0 => return,
+                Ok(_) => break,
+                Err(x) => val = x,
+            }
+        }
+        let _ = futex_wake::<Private>(&self.futex, 1);
+    }
+}
+
+/// Works under the write lock for a bit, then outside of it.
+fn writers<P: Policy>(threads: usize, policy: P, work: usize) -> Duration {
+    contend(threads, RwLock::with_policy(policy), move |lock| {
+        lock.acquire_write();
+        for _ in 0..work {
+            hint::black_box(());
+        }
+        lock.release_write(());
+        for _ in 0..work {
+            hint::black_box(());
+        }
+    })
+}
+
+/// Same with `RacingWriters`.
+fn racing_writers(threads: usize, work: usize) -> Duration {
+    contend(threads, RacingWriters::default(), move |lock| {
+        lock.acquire_write();
+        for _ in 0..work {
+            hint::black_box(());
+        }
+        lock.release_write();
+        for _ in 0..work {
+            hint::black_box(());
+        }
+    })
+}
+
+fn main() {
+    println!("{} iterations per thread", ITERATIONS);
+    for &threads in &[8, 16] {
+        for &work in &[1, 100] {
+            println!("{:>2} writers, {:>3} work: racing {:>8?}/iter  writer-preferring {:>8?}/iter  reader-preferring {:>8?}/iter  phase-fair {:>8?}/iter",
+                     threads, work, racing_writers(threads, work), writers(threads, WriterPreferring, work),
+                     writers(threads, ReaderPreferring, work), writers(threads, PhaseFair, work));
+        }
+    }
+}
@@ -2,6 +2,7 @@
 extern crate libc;
 extern crate futex;
 
+use futex::RawRwLock;
 use futex::raw::RwLock;
 use std::thread;
 use std::sync::Arc;
@@ -17,28 +18,28 @@ fn main() {
         futex2.acquire_read();
         println!("thread reader");
         thread::sleep_ms(100);
-        futex2.release_read();
+        futex2.release_read(());
         futex2.acquire_write();
         println!("thread writer");
         thread::sleep_ms(100);
-        futex2.release_write();
+        futex2.release_write(());
         thread::sleep_ms(100);
         futex2.acquire_read();
         println!("thread reader 2");
         thread::sleep_ms(100);
-        futex2.release_read();
+        futex2.release_read(());
     });
     thread::sleep_ms(100);
-    futex.release_read();
-    futex.release_read();
+    futex.release_read(());
+    futex.release_read(());
     println!("last reader going down");
     thread::sleep_ms(100);
-    futex.release_read();
+    futex.release_read(());
     thread::sleep_ms(100);
     futex.acquire_write();
     println!("main writer");
     thread::sleep_ms(100);
-    futex.release_write();
+    futex.release_write(());
     thread.join().unwrap();
     println!("done");
 }
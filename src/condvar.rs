@@ -0,0 +1,177 @@
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use sys::{futex_cmp_requeue, futex_wait, futex_wait_for, futex_wake, waited, woken, Private};
+use error::Error;
+use {raw, MutexGuard};
+
+/// A condition variable.
+///
+/// Waiting threads sleep on a sequence counter that is bumped
+/// by every notification, so a notification that happens after
+/// a waiter released the mutex (but before it actually went to sleep)
+/// is never lost.
+///
+/// Instead of waking up every waiter at once, `notify_all` wakes up a single one
+/// and moves the others straight over to wait on the mutex. This requires a
+/// condition variable to always be used with the same mutex.
+///
+/// Spurious wakeups are possible, just like with `std::sync::Condvar`.
+/// Without futexes, they are all there is: waiting just yields the CPU once.
+pub struct Condvar {
+    seq: AtomicI32,
+    // address of the futex word of the mutex we're used with (0 = none yet)
+    mutex: AtomicUsize,
+}
+
+/// Whether a timed wait on a condition variable timed out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaitTimeoutResult(bool);
+
+impl WaitTimeoutResult {
+    /// Returns `true` if the wait is known to have timed out.
+    pub fn timed_out(&self) -> bool {
+        self.0
+    }
+}
+
+impl Condvar {
+    /// Creates a new condition variable.
+    pub fn new() -> Condvar {
+        Condvar {
+            seq: AtomicI32::new(0),
+            mutex: AtomicUsize::new(0),
+        }
+    }
+
+    /// Remembers the mutex that waiters may be requeued onto.
+    fn bind(&self, mutex: &raw::Mutex) {
+        let addr = mutex.futex() as *const _ as usize;
+        match self.mutex.compare_exchange(0, addr, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => (),
+            Err(x) if x == addr => (),
+            Err(_) => panic!("attempted to use a condition variable with two mutexes"),
+        }
+    }
+
+    /// Blocks until this condition variable is notified.
+    ///
+    /// The mutex is released while waiting and re-acquired before returning.
+    pub fn wait<'a, T>(&self, mut guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        // read the counter while still holding the lock
+        // so we can't miss a notification
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.bind(guard.raw());
+        guard.unlocked(|mutex| {
+            waited(futex_wait::<Private>(&self.seq, seq), None);
+            // we might have been requeued
+            ((), mutex.lock_contended())
+        });
+        guard
+    }
+
+    /// Blocks as long as `condition` returns `true`.
+    pub fn wait_while<'a, T, F>(&self, mut guard: MutexGuard<'a, T>, mut condition: F) -> MutexGuard<'a, T>
+        where F: FnMut(&mut T) -> bool {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Blocks until this condition variable is notified or `timeout` has elapsed.
+    pub fn wait_timeout<'a, T>(&self, mut guard: MutexGuard<'a, T>, timeout: Duration)
+                               -> (MutexGuard<'a, T>, WaitTimeoutResult) {
+        let seq = self.seq.load(Ordering::Relaxed);
+        let deadline = Instant::now().checked_add(timeout);
+        self.bind(guard.raw());
+        let timed_out = guard.unlocked(|mutex| {
+            let timed_out = !waited(futex_wait_for::<Private>(&self.seq, seq, timeout), deadline);
+            // we might have been requeued
+            (timed_out, mutex.lock_contended())
+        });
+        (guard, WaitTimeoutResult(timed_out))
+    }
+
+    /// Wakes up one blocked thread.
+    pub fn notify_one(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        woken(futex_wake::<Private>(&self.seq, 1));
+    }
+
+    /// Wakes up all blocked threads.
+    ///
+    /// Only one of them is actually woken up right away, the others are requeued
+    /// onto the mutex as they would only fight over it anyways.
+    pub fn notify_all(&self) {
+        let seq = self.seq.fetch_add(1, Ordering::Release).wrapping_add(1);
+        let mutex = self.mutex.load(Ordering::Relaxed);
+        if mutex == 0 {
+            // nobody ever waited
+            return;
+        }
+
+        // the mutex is still alive if there are any waiters as they borrow it
+        // (if there aren't, the requeue doesn't touch it)
+        let mutex = unsafe { &*(mutex as *const AtomicI32) };
+        match futex_cmp_requeue::<Private>(&self.seq, seq, 1, i32::MAX, mutex) {
+            Ok(_) => (),
+            // someone notified in the meantime, their requeue covers everyone
+            Err(Error::WouldBlock) => (),
+            Err(_) => {
+                woken(futex_wake::<Private>(&self.seq, i32::MAX));
+            }
+        }
+    }
+}
+
+impl Default for Condvar {
+    /// Creates a new instance.
+    fn default() -> Condvar {
+        Condvar::new()
+    }
+}
+
+impl Debug for Condvar {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "Condvar@{:p} (={})", &self.seq as *const _, self.seq.load(Ordering::SeqCst))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+    use std::sync::Arc;
+    use raw;
+    use super::*;
+    use Mutex;
+
+    #[test]
+    fn condvar() {
+        let pair = Arc::new((Mutex::new(raw::Mutex::new(), 0), Condvar::new()));
+        let workers: Vec<_> = (0..4).map(|_| {
+            let pair = pair.clone();
+            thread::spawn(move || {
+                let (ref mutex, ref cond) = *pair;
+                let guard = cond.wait_while(mutex.lock(), |started| *started == 0);
+                assert_eq!(*guard, 1);
+            })
+        }).collect();
+
+        thread::sleep(Duration::from_millis(50));
+        *pair.0.lock() = 1;
+        pair.1.notify_all();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn condvar_timeout() {
+        let mutex = Mutex::new(raw::Mutex::new(), ());
+        let cond = Condvar::new();
+        let (_guard, res) = cond.wait_timeout(mutex.lock(), Duration::from_millis(50));
+        assert!(res.timed_out());
+    }
+}
@@ -0,0 +1,177 @@
+use std::error::Error as StdError;
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::io;
+use libc;
+
+/// An error reported by a futex operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The futex word didn't hold the expected value (`EAGAIN`).
+    WouldBlock,
+    /// The wait was interrupted by a signal (`EINTR`).
+    Interrupted,
+    /// The timeout expired (`ETIMEDOUT`).
+    TimedOut,
+    /// The kernel doesn't support the operation (`ENOSYS`),
+    /// e.g. because it's too old or a seccomp filter rejects it.
+    ///
+    /// The locks in this crate don't report this: their waiters yield the CPU
+    /// in a loop instead of sleeping then. Apart from `PiMutex`, they do the
+    /// same when a seccomp filter rejects futexes with `EPERM`.
+    Unsupported,
+    /// The futex word is not accessible (`EFAULT`).
+    Fault,
+    /// Any other error, identified by its `errno`.
+    Other(i32),
+}
+
+impl Error {
+    /// Creates an error from an `errno` value.
+    pub fn from_errno(errno: i32) -> Error {
+        match errno {
+            libc::EAGAIN => Error::WouldBlock,
+            libc::EINTR => Error::Interrupted,
+            libc::ETIMEDOUT => Error::TimedOut,
+            libc::ENOSYS => Error::Unsupported,
+            libc::EFAULT => Error::Fault,
+            errno => Error::Other(errno),
+        }
+    }
+
+    /// Returns the `errno` value of this error.
+    pub fn errno(&self) -> i32 {
+        match *self {
+            Error::WouldBlock => libc::EAGAIN,
+            Error::Interrupted => libc::EINTR,
+            Error::TimedOut => libc::ETIMEDOUT,
+            Error::Unsupported => libc::ENOSYS,
+            Error::Fault => libc::EFAULT,
+            Error::Other(errno) => errno,
+        }
+    }
+
+    /// The error of the last failed syscall on this thread.
+    pub(crate) fn last() -> Error {
+        Error::from_errno(io::Error::last_os_error().raw_os_error().unwrap_or(0))
+    }
+
+    /// Whether futexes can't be used here at all.
+    ///
+    /// Seccomp filters usually reject forbidden syscalls with `ENOSYS` or `EPERM`.
+    /// The latter also has a real meaning for the PI operations, so don't ask this there.
+    pub(crate) fn is_unavailable(&self) -> bool {
+        matches!(*self, Error::Unsupported | Error::Other(libc::EPERM))
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "futex operation failed: {}", io::Error::from_raw_os_error(self.errno()))
+    }
+}
+
+impl StdError for Error {}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        io::Error::from_raw_os_error(e.errno())
+    }
+}
+
+/// The error returned when a timed lock operation gave up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeoutError;
+
+impl Display for TimeoutError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "timed out waiting for the lock")
+    }
+}
+
+impl StdError for TimeoutError {}
+
+/// The error returned when the previous owner of a robust lock died while holding it.
+///
+/// The lock has been acquired regardless, so the protected data can be repaired
+/// through the guard inside.
+pub struct OwnerDiedError<G> {
+    guard: G,
+}
+
+impl<G> OwnerDiedError<G> {
+    pub(crate) fn new(guard: G) -> OwnerDiedError<G> {
+        OwnerDiedError { guard }
+    }
+
+    /// Consumes the error, returning the guard.
+    pub fn into_inner(self) -> G {
+        self.guard
+    }
+
+    /// Returns a reference to the guard.
+    pub fn get_ref(&self) -> &G {
+        &self.guard
+    }
+
+    /// Returns a mutable reference to the guard.
+    pub fn get_mut(&mut self) -> &mut G {
+        &mut self.guard
+    }
+}
+
+impl<G> Debug for OwnerDiedError<G> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("OwnerDiedError").finish_non_exhaustive()
+    }
+}
+
+impl<G> Display for OwnerDiedError<G> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "the previous owner of the lock died while holding it")
+    }
+}
+
+impl<G> StdError for OwnerDiedError<G> {}
+
+/// The error returned when a thread panicked while holding a poisoning lock (see `poison`).
+///
+/// The lock has been acquired regardless, so the protected data can be inspected
+/// (and repaired) through the guard inside (or the data itself, for `into_inner` and `get_mut`).
+pub struct PoisonError<G> {
+    guard: G,
+}
+
+impl<G> PoisonError<G> {
+    pub(crate) fn new(guard: G) -> PoisonError<G> {
+        PoisonError { guard }
+    }
+
+    /// Consumes the error, returning the guard.
+    pub fn into_inner(self) -> G {
+        self.guard
+    }
+
+    /// Returns a reference to the guard.
+    pub fn get_ref(&self) -> &G {
+        &self.guard
+    }
+
+    /// Returns a mutable reference to the guard.
+    pub fn get_mut(&mut self) -> &mut G {
+        &mut self.guard
+    }
+}
+
+impl<G> Debug for PoisonError<G> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("PoisonError").finish_non_exhaustive()
+    }
+}
+
+impl<G> Display for PoisonError<G> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "a thread panicked while holding the lock")
+    }
+}
+
+impl<G> StdError for PoisonError<G> {}
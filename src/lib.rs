@@ -1,16 +1,52 @@
-#![cfg_attr(feature = "nightly", feature(integer_atomics, core_intrinsics))]
+#![cfg_attr(feature = "nightly", feature(core_intrinsics))]
 
 extern crate libc;
-extern crate integer_atomics;
 extern crate lock_wrappers;
 
-mod sys;
+pub mod sys;
 pub mod raw;
+pub mod mutex;
+pub mod rwlock;
+pub mod poison;
+pub mod shared;
+mod condvar;
+mod error;
+mod pi;
+mod robust;
+mod traits;
+mod wait;
 
 pub use lock_wrappers::raw::{Mutex as RawMutex, RwLock as RawRwLock};
+pub use traits::{DowngradableRwLock as RawDowngradableRwLock, PoisonableLock as RawPoisonableLock,
+                 TimedMutex as RawTimedMutex, TimedRwLock as RawTimedRwLock, TryRwLock as RawTryRwLock,
+                 UpgradableRwLock as RawUpgradableRwLock};
+pub use error::{Error, OwnerDiedError, PoisonError, TimeoutError};
+pub use pi::{PiMutex, PiMutexGuard};
+pub use robust::{RobustLockResult, RobustMutex, RobustMutexGuard};
+pub use poison::LockResult;
+pub use shared::SharedMutex;
+#[cfg(target_has_atomic = "64")]
+pub use shared::SharedRwLock;
+pub use wait::{wait_any, wake};
+pub use condvar::{Condvar, WaitTimeoutResult};
 
-pub type Mutex<T> = lock_wrappers::Mutex<raw::Mutex, T>;
-pub type MutexGuard<'a, T> = lock_wrappers::MutexGuard<'a, raw::Mutex, T>;
-pub type RwLock<T> = lock_wrappers::RwLock<raw::RwLock, T>;
-pub type RwLockReadGuard<'a, T> = lock_wrappers::RwLockReadGuard<'a, raw::RwLock, T>;
-pub type RwLockWriteGuard<'a, T> = lock_wrappers::RwLockWriteGuard<'a, raw::RwLock, T>;
+pub type Mutex<T> = mutex::Mutex<raw::Mutex, T>;
+pub type MutexGuard<'a, T> = mutex::MutexGuard<'a, raw::Mutex, T>;
+pub type FairMutex<T> = mutex::Mutex<raw::FairMutex, T>;
+pub type FairMutexGuard<'a, T> = mutex::MutexGuard<'a, raw::FairMutex, T>;
+#[cfg(target_has_atomic = "64")]
+pub type RwLock<T> = rwlock::RwLock<raw::RwLock, T>;
+#[cfg(target_has_atomic = "64")]
+pub type RwLockReadGuard<'a, T> = rwlock::RwLockReadGuard<'a, raw::RwLock, T>;
+#[cfg(target_has_atomic = "64")]
+pub type RwLockWriteGuard<'a, T> = rwlock::RwLockWriteGuard<'a, raw::RwLock, T>;
+#[cfg(target_has_atomic = "64")]
+pub type RwLockUpgradableReadGuard<'a, T> = rwlock::RwLockUpgradableReadGuard<'a, raw::RwLock, T>;
+pub type PoisonMutex<T> = poison::Mutex<raw::Mutex, T>;
+pub type PoisonMutexGuard<'a, T> = poison::MutexGuard<'a, raw::Mutex, T>;
+#[cfg(target_has_atomic = "64")]
+pub type PoisonRwLock<T> = poison::RwLock<raw::RwLock, T>;
+#[cfg(target_has_atomic = "64")]
+pub type PoisonRwLockReadGuard<'a, T> = poison::RwLockReadGuard<'a, raw::RwLock, T>;
+#[cfg(target_has_atomic = "64")]
+pub type PoisonRwLockWriteGuard<'a, T> = poison::RwLockWriteGuard<'a, raw::RwLock, T>;
@@ -0,0 +1,156 @@
+//! A mutex wrapper generic over the raw lock.
+//!
+//! This mirrors `lock_wrappers::Mutex` but additionally exposes
+//! the extended capabilities of the raw locks in this crate.
+
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::time::{Duration, Instant};
+use lock_wrappers::raw::Mutex as RawMutex;
+use traits::TimedMutex;
+use error::TimeoutError;
+
+pub struct Mutex<L: RawMutex, T> {
+    mutex: L,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<L: RawMutex, T: Send> Send for Mutex<L, T> { }
+unsafe impl<L: RawMutex, T: Send> Sync for Mutex<L, T> { }
+
+impl<L: RawMutex, T> Mutex<L, T> {
+    pub fn new(l: L, t: T) -> Mutex<L, T> {
+        Mutex {
+            mutex: l,
+            data: UnsafeCell::new(t),
+        }
+    }
+
+    /// Returns the raw lock.
+    pub(crate) fn raw(&self) -> &L {
+        &self.mutex
+    }
+
+    /// Consumes the mutex, returning the data.
+    pub(crate) fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+
+    /// Returns a mutable reference to the data.
+    ///
+    /// No locking is needed since this borrows the mutex mutably.
+    pub(crate) fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, L, T> {
+        MutexGuard {
+            state: Some(self.mutex.lock()),
+            mutex: self,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, L, T>> {
+        self.mutex.try_lock().map(|x| MutexGuard {
+            state: Some(x),
+            mutex: self,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<L: TimedMutex, T> Mutex<L, T> {
+    /// Attempts to lock the mutex, blocking for `timeout` at most.
+    pub fn try_lock_for(&self, timeout: Duration) -> Result<MutexGuard<'_, L, T>, TimeoutError> {
+        match self.mutex.try_lock_for(timeout) {
+            Some(x) => Ok(MutexGuard {
+                state: Some(x),
+                mutex: self,
+                _marker: PhantomData,
+            }),
+            None => Err(TimeoutError),
+        }
+    }
+
+    /// Attempts to lock the mutex, blocking until `deadline` at most.
+    pub fn try_lock_until(&self, deadline: Instant) -> Result<MutexGuard<'_, L, T>, TimeoutError> {
+        match self.mutex.try_lock_until(deadline) {
+            Some(x) => Ok(MutexGuard {
+                state: Some(x),
+                mutex: self,
+                _marker: PhantomData,
+            }),
+            None => Err(TimeoutError),
+        }
+    }
+}
+
+impl<L: RawMutex + Default, T: Default> Default for Mutex<L, T> {
+    /// Creates a `Mutex<T>`, with the `Default` value for T.
+    fn default() -> Mutex<L, T> {
+        Mutex::new(Default::default(), Default::default())
+    }
+}
+
+/// The guard of a `Mutex`.
+///
+/// It can only be shared between threads if the data can be:
+///
+/// ```compile_fail,E0277
+/// use std::cell::Cell;
+/// fn sync<T: Sync>(_: &T) {}
+/// let mutex = futex::Mutex::new(Default::default(), Cell::new(0));
+/// sync(&mutex.lock());
+/// ```
+#[must_use]
+pub struct MutexGuard<'a, L: RawMutex + 'a, T: 'a> {
+    state: Option<L::LockState>,
+    mutex: &'a Mutex<L, T>,
+    // like std's guard, this is neither Send (some locks have to be released by their owner)
+    // nor Sync unless T is (the derived impl would only need T: Send)
+    _marker: PhantomData<*const ()>,
+}
+
+unsafe impl<'a, L: RawMutex + 'a, T: Sync + 'a> Sync for MutexGuard<'a, L, T> { }
+
+impl<'a, L: RawMutex + 'a, T: 'a> MutexGuard<'a, L, T> {
+    /// Returns the raw lock.
+    pub(crate) fn raw(&self) -> &'a L {
+        &self.mutex.mutex
+    }
+
+    /// Runs `f` with the lock temporarily released.
+    ///
+    /// `f` gets the raw lock and has to hand back the state of a new acquisition.
+    pub(crate) fn unlocked<F: FnOnce(&L) -> (R, L::LockState), R>(&mut self, f: F) -> R {
+        self.mutex.mutex.unlock(self.state.take().unwrap());
+        let (ret, state) = f(&self.mutex.mutex);
+        self.state = Some(state);
+        ret
+    }
+}
+
+impl<'a, L: RawMutex + 'a, T: 'a> Drop for MutexGuard<'a, L, T> {
+    fn drop(&mut self) {
+        // (the state may be missing if we panicked while temporarily unlocked)
+        if let Some(state) = self.state.take() {
+            self.mutex.mutex.unlock(state);
+        }
+    }
+}
+
+impl<'a, L: RawMutex + 'a, T: 'a> Deref for MutexGuard<'a, L, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, L: RawMutex + 'a, T: 'a> DerefMut for MutexGuard<'a, L, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
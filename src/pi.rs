@@ -0,0 +1,97 @@
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::time::{Duration, Instant};
+use lock_wrappers::raw::Mutex as RawMutex;
+use traits::TimedMutex;
+use error::TimeoutError;
+use raw;
+
+/// A priority-inheritance mutex.
+///
+/// See `raw::PiMutex` for the details.
+pub struct PiMutex<T> {
+    mutex: raw::PiMutex,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for PiMutex<T> { }
+unsafe impl<T: Send> Sync for PiMutex<T> { }
+
+impl<T> PiMutex<T> {
+    pub fn new(t: T) -> PiMutex<T> {
+        PiMutex {
+            mutex: raw::PiMutex::new(),
+            data: UnsafeCell::new(t),
+        }
+    }
+
+    pub fn lock(&self) -> PiMutexGuard<'_, T> {
+        self.mutex.lock();
+        self.guard()
+    }
+
+    pub fn try_lock(&self) -> Option<PiMutexGuard<'_, T>> {
+        self.mutex.try_lock().map(|()| self.guard())
+    }
+
+    /// Attempts to lock the mutex, blocking for `timeout` at most.
+    pub fn try_lock_for(&self, timeout: Duration) -> Result<PiMutexGuard<'_, T>, TimeoutError> {
+        self.mutex.try_lock_for(timeout).map(|()| self.guard()).ok_or(TimeoutError)
+    }
+
+    /// Attempts to lock the mutex, blocking until `deadline` at most.
+    pub fn try_lock_until(&self, deadline: Instant) -> Result<PiMutexGuard<'_, T>, TimeoutError> {
+        self.mutex.try_lock_until(deadline).map(|()| self.guard()).ok_or(TimeoutError)
+    }
+
+    fn guard(&self) -> PiMutexGuard<'_, T> {
+        PiMutexGuard {
+            mutex: self,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl<T: Default> Default for PiMutex<T> {
+    fn default() -> PiMutex<T> {
+        PiMutex::new(Default::default())
+    }
+}
+
+/// The guard of a `PiMutex`.
+///
+/// It can't be sent to other threads as the lock has to be released by its owner:
+///
+/// ```compile_fail,E0277
+/// fn send<T: Send>(_: T) {}
+/// let mutex = futex::PiMutex::new(0);
+/// send(mutex.lock());
+/// ```
+#[must_use]
+pub struct PiMutexGuard<'a, T: 'a> {
+    mutex: &'a PiMutex<T>,
+    _not_send: PhantomData<*const ()>,
+}
+
+unsafe impl<'a, T: Sync + 'a> Sync for PiMutexGuard<'a, T> { }
+
+impl<'a, T: 'a> Drop for PiMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.mutex.unlock(());
+    }
+}
+
+impl<'a, T: 'a> Deref for PiMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T: 'a> DerefMut for PiMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
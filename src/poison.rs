@@ -0,0 +1,392 @@
+//! Lock wrappers that are poisoned when a thread panics while holding them.
+//!
+//! These mirror the wrappers in `mutex` and `rwlock`, but like the locks in
+//! `std::sync`, they remember that a thread panicked while holding the (write)
+//! lock, which may have left the data half-updated. Everyone who acquires
+//! the lock afterwards gets the guard wrapped in a `PoisonError` until the
+//! poison is cleared.
+//!
+//! The flag lives in the state of the raw lock (see `RawPoisonableLock`),
+//! so checking it doesn't cost anything extra.
+
+use std::ops::{Deref, DerefMut};
+use std::thread;
+use std::time::{Duration, Instant};
+use lock_wrappers::raw::{Mutex as RawMutex, RwLock as RawRwLock};
+use traits::{PoisonableLock, TimedMutex, TryRwLock};
+use error::{PoisonError, TimeoutError};
+use {mutex, rwlock};
+
+/// The result of acquiring a poisoning lock.
+pub type LockResult<G> = Result<G, PoisonError<G>>;
+
+fn result<G>(poisoned: bool, guard: G) -> LockResult<G> {
+    if poisoned {
+        Err(PoisonError::new(guard))
+    } else {
+        Ok(guard)
+    }
+}
+
+pub struct Mutex<L: RawMutex + PoisonableLock, T> {
+    mutex: mutex::Mutex<L, T>,
+}
+
+impl<L: RawMutex + PoisonableLock, T> Mutex<L, T> {
+    pub fn new(l: L, t: T) -> Mutex<L, T> {
+        Mutex {
+            mutex: mutex::Mutex::new(l, t),
+        }
+    }
+
+    /// Acquires the lock.
+    ///
+    /// If the lock is poisoned, it is acquired anyways
+    /// but the guard is wrapped in an error.
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, L, T>> {
+        MutexGuard::new(self.mutex.lock())
+    }
+
+    /// Attempts to acquire the lock without blocking.
+    pub fn try_lock(&self) -> Option<LockResult<MutexGuard<'_, L, T>>> {
+        self.mutex.try_lock().map(MutexGuard::new)
+    }
+
+    /// Whether a thread panicked while holding the lock.
+    pub fn is_poisoned(&self) -> bool {
+        self.mutex.raw().is_poisoned()
+    }
+
+    /// Clears the poisoned state (e.g. after repairing the data).
+    pub fn clear_poison(&self) {
+        self.mutex.raw().clear_poison();
+    }
+
+    /// Consumes the mutex, returning the data.
+    ///
+    /// If the mutex is poisoned, the data is wrapped in an error.
+    pub fn into_inner(self) -> LockResult<T> {
+        let poisoned = self.is_poisoned();
+        result(poisoned, self.mutex.into_inner())
+    }
+
+    /// Returns a mutable reference to the data.
+    ///
+    /// If the mutex is poisoned, the reference is wrapped in an error.
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        let poisoned = self.is_poisoned();
+        result(poisoned, self.mutex.get_mut())
+    }
+}
+
+impl<L: TimedMutex + PoisonableLock, T> Mutex<L, T> {
+    /// Attempts to acquire the lock, blocking for `timeout` at most.
+    pub fn try_lock_for(&self, timeout: Duration) -> Result<LockResult<MutexGuard<'_, L, T>>, TimeoutError> {
+        self.mutex.try_lock_for(timeout).map(MutexGuard::new)
+    }
+
+    /// Attempts to acquire the lock, blocking until `deadline` at most.
+    pub fn try_lock_until(&self, deadline: Instant) -> Result<LockResult<MutexGuard<'_, L, T>>, TimeoutError> {
+        self.mutex.try_lock_until(deadline).map(MutexGuard::new)
+    }
+}
+
+impl<L: RawMutex + PoisonableLock + Default, T: Default> Default for Mutex<L, T> {
+    /// Creates a `Mutex<T>`, with the `Default` value for T.
+    fn default() -> Mutex<L, T> {
+        Mutex::new(Default::default(), Default::default())
+    }
+}
+
+#[must_use]
+pub struct MutexGuard<'a, L: RawMutex + PoisonableLock + 'a, T: 'a> {
+    guard: mutex::MutexGuard<'a, L, T>,
+    // (a panic that was already going on when we got the lock isn't our fault)
+    panicking: bool,
+}
+
+impl<'a, L: RawMutex + PoisonableLock + 'a, T: 'a> MutexGuard<'a, L, T> {
+    fn new(guard: mutex::MutexGuard<'a, L, T>) -> LockResult<MutexGuard<'a, L, T>> {
+        result(guard.raw().is_poisoned(), MutexGuard {
+            guard,
+            panicking: thread::panicking(),
+        })
+    }
+}
+
+impl<'a, L: RawMutex + PoisonableLock + 'a, T: 'a> Drop for MutexGuard<'a, L, T> {
+    fn drop(&mut self) {
+        // (the lock is released right after this, when the inner guard is dropped)
+        if !self.panicking && thread::panicking() {
+            self.guard.raw().poison();
+        }
+    }
+}
+
+impl<'a, L: RawMutex + PoisonableLock + 'a, T: 'a> Deref for MutexGuard<'a, L, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, L: RawMutex + PoisonableLock + 'a, T: 'a> DerefMut for MutexGuard<'a, L, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+/// A poisoning reader-writer lock.
+///
+/// Only panicking writers poison the lock, readers can't have left anything half-updated.
+pub struct RwLock<L: RawRwLock + PoisonableLock, T> {
+    rwlock: rwlock::RwLock<L, T>,
+}
+
+impl<L: RawRwLock + PoisonableLock, T> RwLock<L, T> {
+    pub fn new_custom(l: L, t: T) -> RwLock<L, T> {
+        RwLock {
+            rwlock: rwlock::RwLock::new_custom(l, t),
+        }
+    }
+
+    /// Acquires a read lock.
+    ///
+    /// If the lock is poisoned, it is acquired anyways
+    /// but the guard is wrapped in an error.
+    #[inline]
+    pub fn read(&self) -> LockResult<RwLockReadGuard<'_, L, T>> {
+        // (only once we have it, a writer we waited for may have panicked)
+        let guard = self.rwlock.read();
+        result(self.is_poisoned(), RwLockReadGuard { guard })
+    }
+
+    /// Acquires a write lock.
+    ///
+    /// If the lock is poisoned, it is acquired anyways
+    /// but the guard is wrapped in an error.
+    #[inline]
+    pub fn write(&self) -> LockResult<RwLockWriteGuard<'_, L, T>> {
+        RwLockWriteGuard::new(self.rwlock.write())
+    }
+
+    /// Whether a thread panicked while holding the write lock.
+    pub fn is_poisoned(&self) -> bool {
+        self.rwlock.raw().is_poisoned()
+    }
+
+    /// Clears the poisoned state (e.g. after repairing the data).
+    pub fn clear_poison(&self) {
+        self.rwlock.raw().clear_poison();
+    }
+
+    /// Consumes the lock, returning the data.
+    ///
+    /// If the lock is poisoned, the data is wrapped in an error.
+    pub fn into_inner(self) -> LockResult<T> {
+        let poisoned = self.is_poisoned();
+        result(poisoned, self.rwlock.into_inner())
+    }
+
+    /// Returns a mutable reference to the data.
+    ///
+    /// If the lock is poisoned, the reference is wrapped in an error.
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        let poisoned = self.is_poisoned();
+        result(poisoned, self.rwlock.get_mut())
+    }
+}
+
+impl<L: TryRwLock + PoisonableLock, T> RwLock<L, T> {
+    /// Attempts to acquire a read lock without blocking.
+    #[inline]
+    pub fn try_read(&self) -> Option<LockResult<RwLockReadGuard<'_, L, T>>> {
+        self.rwlock.try_read().map(|guard| result(self.is_poisoned(), RwLockReadGuard { guard }))
+    }
+
+    /// Attempts to acquire a write lock without blocking.
+    #[inline]
+    pub fn try_write(&self) -> Option<LockResult<RwLockWriteGuard<'_, L, T>>> {
+        self.rwlock.try_write().map(RwLockWriteGuard::new)
+    }
+}
+
+impl<L: RawRwLock + PoisonableLock + Default, T> RwLock<L, T> {
+    pub fn new(t: T) -> RwLock<L, T> {
+        RwLock {
+            rwlock: rwlock::RwLock::new(t),
+        }
+    }
+}
+
+impl<L: RawRwLock + PoisonableLock + Default, T: Default> Default for RwLock<L, T> {
+    fn default() -> RwLock<L, T> {
+        RwLock::new(Default::default())
+    }
+}
+
+#[must_use]
+pub struct RwLockReadGuard<'a, L: RawRwLock + PoisonableLock + 'a, T: 'a> {
+    guard: rwlock::RwLockReadGuard<'a, L, T>,
+}
+
+#[must_use]
+pub struct RwLockWriteGuard<'a, L: RawRwLock + PoisonableLock + 'a, T: 'a> {
+    guard: rwlock::RwLockWriteGuard<'a, L, T>,
+    // (see MutexGuard)
+    panicking: bool,
+}
+
+impl<'a, L: RawRwLock + PoisonableLock + 'a, T: 'a> RwLockWriteGuard<'a, L, T> {
+    fn new(guard: rwlock::RwLockWriteGuard<'a, L, T>) -> LockResult<RwLockWriteGuard<'a, L, T>> {
+        result(guard.raw().is_poisoned(), RwLockWriteGuard {
+            guard,
+            panicking: thread::panicking(),
+        })
+    }
+}
+
+impl<'a, L: RawRwLock + PoisonableLock + 'a, T: 'a> Drop for RwLockWriteGuard<'a, L, T> {
+    fn drop(&mut self) {
+        // (the lock is released right after this, when the inner guard is dropped)
+        if !self.panicking && thread::panicking() {
+            self.guard.raw().poison();
+        }
+    }
+}
+
+impl<'a, L: RawRwLock + PoisonableLock + 'a, T: 'a> Deref for RwLockReadGuard<'a, L, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, L: RawRwLock + PoisonableLock + 'a, T: 'a> Deref for RwLockWriteGuard<'a, L, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, L: RawRwLock + PoisonableLock + 'a, T: 'a> DerefMut for RwLockWriteGuard<'a, L, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // (the reader-writer lock tests need 64-bit atomics)
+    #![cfg_attr(not(target_has_atomic = "64"), allow(unused_imports))]
+    use std::panic;
+    use std::sync::Arc;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+    use {PoisonMutex, raw};
+    #[cfg(target_has_atomic = "64")]
+    use PoisonRwLock;
+
+    #[test]
+    fn poison_mutex() {
+        let mutex = Arc::new(PoisonMutex::new(raw::Mutex::new(), 0));
+        let m = mutex.clone();
+        assert!(thread::spawn(move || {
+            let mut guard = m.lock().unwrap();
+            *guard = 1;
+            panic!("oops");
+        }).join().is_err());
+
+        assert!(mutex.is_poisoned());
+        let mut guard = mutex.lock().err().unwrap().into_inner();
+        assert_eq!(*guard, 1);
+        *guard = 2;
+        drop(guard);
+        assert!(mutex.try_lock().unwrap().is_err());
+
+        mutex.clear_poison();
+        assert_eq!(*mutex.lock().unwrap(), 2);
+
+        let mut mutex = Arc::try_unwrap(mutex).ok().unwrap();
+        *mutex.get_mut().unwrap() = 3;
+        assert!(panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let _guard = mutex.lock().unwrap();
+            panic!("oops");
+        })).is_err());
+        assert_eq!(*mutex.get_mut().err().unwrap().into_inner(), 3);
+        assert_eq!(mutex.into_inner().err().unwrap().into_inner(), 3);
+    }
+
+    #[test]
+    fn poison_mutex_panicking() {
+        // acquiring the lock while already unwinding doesn't poison it
+        let mutex = PoisonMutex::new(raw::Mutex::new(), ());
+        struct Unwind<'a>(&'a PoisonMutex<()>);
+        impl<'a> Drop for Unwind<'a> {
+            fn drop(&mut self) {
+                let _guard = self.0.lock().unwrap();
+            }
+        }
+        assert!(panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let _unwind = Unwind(&mutex);
+            panic!("oops");
+        })).is_err());
+        assert!(!mutex.is_poisoned());
+    }
+
+    #[test]
+    #[cfg(target_has_atomic = "64")]
+    fn poison_rwlock() {
+        let rwlock = Arc::new(PoisonRwLock::new(0));
+        let r = rwlock.clone();
+        assert!(thread::spawn(move || {
+            let _guard = r.read().unwrap();
+            panic!("oops");
+        }).join().is_err());
+        assert!(!rwlock.is_poisoned());
+
+        let r = rwlock.clone();
+        assert!(thread::spawn(move || {
+            *r.write().unwrap() = 1;
+            let _guard = r.write().unwrap();
+            panic!("oops");
+        }).join().is_err());
+
+        assert!(rwlock.is_poisoned());
+        assert_eq!(*rwlock.read().err().unwrap().into_inner(), 1);
+        assert!(rwlock.try_write().unwrap().is_err());
+        rwlock.clear_poison();
+        assert_eq!(*rwlock.write().unwrap(), 1);
+        let mut rwlock = Arc::try_unwrap(rwlock).ok().unwrap();
+        *rwlock.get_mut().unwrap() = 2;
+        assert_eq!(rwlock.into_inner().unwrap(), 2);
+    }
+
+    #[test]
+    #[cfg(target_has_atomic = "64")]
+    fn poison_rwlock_blocked_reader() {
+        let rwlock = Arc::new(PoisonRwLock::new(0));
+        let (locked_tx, locked_rx) = channel();
+        let (panic_tx, panic_rx) = channel::<()>();
+        let r = rwlock.clone();
+        let writer = thread::spawn(move || {
+            let _guard = r.write().unwrap();
+            locked_tx.send(()).unwrap();
+            panic_rx.recv().unwrap();
+            panic!("oops");
+        });
+        locked_rx.recv().unwrap();
+
+        // a reader that's already waiting when the writer panics finds out too
+        let r = rwlock.clone();
+        let reader = thread::spawn(move || r.read().is_err());
+        thread::sleep(Duration::from_millis(50));
+        panic_tx.send(()).unwrap();
+        assert!(writer.join().is_err());
+        assert!(reader.join().unwrap());
+    }
+}
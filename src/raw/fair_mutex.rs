@@ -0,0 +1,216 @@
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Instant;
+use lock_wrappers::raw::Mutex;
+use sys::{futex_wait_bitset, futex_wait_bitset_until, futex_wake_bitset, monotonic_nanos, waited, woken, Private, Scope};
+use traits::TimedMutex;
+
+const LOCKED: u32     = 0b01;
+// the owner is passing the lock on to the waiter it woke up,
+// so it stays locked until that one takes it
+const HANDOFF: u32    = 0b10;
+const M_WAITERS: u32  = !0b11;
+const ONE_WAITER: u32 = 0b100;
+
+const ID_ANY: i32 = -1;
+
+// how often an eventually fair lock hands the lock over at least
+pub(crate) const FAIR_INTERVAL_NS: u64 = 500_000;
+
+/// How a `FairMutex` passes the lock on to waiters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fairness {
+    /// Always hand the lock over to the next waiter.
+    Strict,
+    /// Let others barge in (which is much faster under contention) but hand
+    /// the lock over to the next waiter every 0.5 ms so nobody starves.
+    Eventual,
+}
+
+/// A fair mutual exclusion lock (mutex).
+///
+/// Instead of waking everyone up to fight over the lock, a release wakes up
+/// a single waiter and hands the lock over to it directly. The kernel queues
+/// waiters of the same priority in FIFO order, so it's first come, first served.
+///
+/// This costs throughput under contention as the lock is unused until the woken
+/// waiter gets to run, which is what `Fairness::Eventual` is for.
+///
+/// This is not designed for direct use but as a building block for locks.
+/// Like `Futex`, it is not reentrant and it may misbehave if used incorrectly.
+/// It also falls back to yielding the CPU where futexes are unavailable.
+#[repr(C)]
+pub struct FairMutex<S: Scope = Private> {
+    futex: AtomicU32,
+    fairness: Fairness,
+    // CLOCK_MONOTONIC nanoseconds of the next forced hand-off (for Fairness::Eventual)
+    // (only ever touched by the owner, so it needs no 64-bit atomics)
+    next_handoff: Cell<u64>,
+    scope: PhantomData<S>,
+}
+
+// next_handoff is protected by the lock itself
+unsafe impl<S: Scope> Sync for FairMutex<S> {}
+
+impl<S: Scope> FairMutex<S> {
+    /// Creates a new, strictly fair instance.
+    pub fn new() -> FairMutex<S> {
+        FairMutex::default()
+    }
+
+    /// Creates a new instance with the given fairness.
+    pub fn with_fairness(fairness: Fairness) -> FairMutex<S> {
+        FairMutex {
+            futex: AtomicU32::new(0),
+            fairness,
+            next_handoff: Cell::new(0),
+            scope: PhantomData,
+        }
+    }
+
+    /// Queues up for the lock.
+    ///
+    /// Returns `false` if we gave up because `deadline` passed.
+    #[inline(never)]
+    fn lock_slow(&self, deadline: Option<Instant>) -> bool {
+        let mut state = self.futex.fetch_add(ONE_WAITER, Ordering::Relaxed).wrapping_add(ONE_WAITER);
+        let mut picked = false;
+        let mut timed_out = false;
+        loop {
+            let (new, acquired) = if (state & LOCKED == 0) || (picked && (state & HANDOFF != 0)) {
+                // free or handed over to us (which leaves it locked) - take it
+                // (a hand-off is only ever for the waiter that was woken up,
+                //  everyone else - including whoever just released it - waits)
+                (((state - ONE_WAITER) & !HANDOFF) | LOCKED, true)
+            } else if timed_out {
+                // we weren't woken up, so if it's being handed over, it's to someone else
+                // (or to nobody, see `unlock`) and nobody misses out if we leave
+                (state - ONE_WAITER, false)
+            } else {
+                let res = match deadline {
+                    None => futex_wait_bitset::<S>(&self.futex, state, ID_ANY),
+                    Some(deadline) => futex_wait_bitset_until::<S>(&self.futex, state, ID_ANY, deadline),
+                };
+                picked = res.is_ok();
+                timed_out = !waited(res, deadline);
+                state = self.futex.load(Ordering::Relaxed);
+                continue;
+            };
+
+            match self.futex.compare_exchange(state, new, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => return acquired,
+                Err(x) => state = x,
+            }
+        }
+    }
+
+    /// Whether this release should hand the lock over to a waiter.
+    fn hand_off(&self) -> bool {
+        match self.fairness {
+            Fairness::Strict => true,
+            Fairness::Eventual => {
+                // only the owner gets here
+                let now = monotonic_nanos();
+                if now >= self.next_handoff.get() {
+                    self.next_handoff.set(now + FAIR_INTERVAL_NS);
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+}
+
+impl<S: Scope> Mutex for FairMutex<S> {
+    type LockState = ();
+
+    /// Acquires the lock.
+    ///
+    /// This blocks until the lock is ours.
+    fn lock(&self) {
+        if self.try_lock().is_none() {
+            self.lock_slow(None);
+        }
+    }
+
+    /// Attempts to acquire the lock without blocking.
+    fn try_lock(&self) -> Option<()> {
+        let state = self.futex.load(Ordering::Relaxed);
+        if state & LOCKED != 0 {
+            return None;
+        }
+        self.futex.compare_exchange(state, state | LOCKED, Ordering::Acquire, Ordering::Relaxed).ok().map(|_| ())
+    }
+
+    /// Releases the lock.
+    fn unlock(&self, _: ()) {
+        let mut state = self.futex.load(Ordering::Relaxed);
+        let mut hand_off = None;
+        loop {
+            let new = if state & M_WAITERS == 0 {
+                // jobs done - no waiters
+                0
+            } else if *hand_off.get_or_insert_with(|| self.hand_off()) {
+                state | HANDOFF
+            } else {
+                state & !LOCKED
+            };
+
+            match self.futex.compare_exchange(state, new, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) if new == 0 => return,
+                Ok(_) => {
+                    state = new;
+                    break;
+                }
+                Err(x) => state = x,
+            }
+        }
+
+        if woken(futex_wake_bitset::<S>(&self.futex, 1, ID_ANY)) != 0 || state & HANDOFF == 0 {
+            return;
+        }
+
+        // nobody is asleep yet (or they all gave up), so there's nobody to hand it over to:
+        // release it after all and let whoever comes first take it
+        let mut state = self.futex.load(Ordering::Relaxed);
+        while state & HANDOFF != 0 {
+            match self.futex.compare_exchange(state, state & !(HANDOFF | LOCKED), Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => {
+                    // someone may have gone to sleep since
+                    woken(futex_wake_bitset::<S>(&self.futex, 1, ID_ANY));
+                    return;
+                }
+                Err(x) => state = x,
+            }
+        }
+    }
+}
+
+impl<S: Scope> TimedMutex for FairMutex<S> {
+    /// Attempts to acquire the lock, blocking until `deadline` at most.
+    ///
+    /// If the lock is handed over to us just as we time out, we take it.
+    fn try_lock_until(&self, deadline: Instant) -> Option<()> {
+        if self.try_lock().is_some() || self.lock_slow(Some(deadline)) {
+            Some(())
+        } else {
+            None
+        }
+    }
+}
+
+impl<S: Scope> Default for FairMutex<S> {
+    /// Creates a new, strictly fair instance.
+    fn default() -> FairMutex<S> {
+        FairMutex::with_fairness(Fairness::Strict)
+    }
+}
+
+impl<S: Scope> Debug for FairMutex<S> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "FairMutex@{:p} (=0x{:08x})", &self.futex as *const _, self.futex.load(Ordering::SeqCst))
+    }
+}
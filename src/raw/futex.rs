@@ -1,9 +1,13 @@
-use std::{io, i32};
+use std::{cmp, hint, ptr};
+#[cfg(test)]
+use std::cell::Cell;
 use std::fmt::{Debug, Formatter, Result as FmtResult};
-use std::sync::atomic::Ordering;
-use integer_atomics::AtomicI32;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::Instant;
 use lock_wrappers::raw::Mutex;
-use sys::{futex_wait, futex_wake};
+use sys::{futex_wait, futex_wait_until, futex_wake, waited, woken, Private, Scope, Shared};
+use traits::{PoisonableLock, TimedMutex};
 
 /// A simple mutual exclusion lock (mutex).
 ///
@@ -12,69 +16,244 @@ use sys::{futex_wait, futex_wake};
 /// Thus, it is not reentrant and it may misbehave if used incorrectly
 /// (i.e. you can release even if someone else is holding it).
 /// It's also not fair.
-pub struct Futex {
-    futex: AtomicI32
+///
+/// When contended, it spins for a while before going to sleep, which saves
+/// short critical sections a syscall and two context switches.
+/// Like glibc's `PTHREAD_MUTEX_ADAPTIVE_NP`, it learns how long it usually
+/// has to spin (see `with_max_spins`).
+///
+/// If futexes can't be used at all (the kernel reports `Error::Unsupported`
+/// or a seccomp filter rejects them with `EPERM`), waiters yield the CPU
+/// in a loop instead of sleeping. The lock keeps working, just less efficiently.
+///
+/// `S` is the `Scope` of the futex: only a `Futex<Shared>` works across
+/// processes when placed in shared memory (see `init_at`).
+#[repr(C)]
+pub struct Futex<S: Scope = Private> {
+    futex: AtomicI32,
+    // how many spins it took to get the lock lately
+    spins: AtomicI32,
+    max_spins: i32,
+    scope: PhantomData<S>,
+}
+
+// the three states from Drepper's "Futexes Are Tricky"
+const UNLOCKED: i32 = 0;
+const LOCKED: i32 = 1;
+// locked and there may be waiters
+const CONTENDED: i32 = 2;
+// a thread panicked while holding the lock (see PoisonableLock)
+// this is kept across the states above, so the fast paths only work while it's clear
+const POISONED: i32 = 4;
+const M_STATE: i32 = 3;
+
+/// The maximum number of spins before going to sleep in `Futex::new`.
+pub const DEFAULT_MAX_SPINS: u32 = 100;
+
+// how many waiters the releases on this thread woke up (see unlock_slow)
+#[cfg(test)]
+thread_local!(pub(crate) static WOKEN: Cell<i32> = const { Cell::new(0) });
+
+impl<S: Scope> Futex<S> {
+    /// Creates a new instance.
+    pub fn new() -> Futex<S> {
+        Futex::default()
+    }
+
+    /// Creates a new instance that spins at most `max_spins` times
+    /// before going to sleep. 0 disables spinning altogether.
+    pub fn with_max_spins(max_spins: u32) -> Futex<S> {
+        Futex {
+            futex: AtomicI32::new(UNLOCKED),
+            spins: AtomicI32::new(0),
+            max_spins: cmp::min(max_spins, i32::MAX as u32) as i32,
+            scope: PhantomData,
+        }
+    }
+
+    /// The futex word itself (e.g. as a requeue target).
+    pub(crate) fn futex(&self) -> &AtomicI32 {
+        &self.futex
+    }
+
+    /// Acquires the lock, leaving it marked as contended.
+    ///
+    /// This is the slow path of `lock`. It's also for threads that may have
+    /// been requeued onto this futex: they never marked the lock as contended
+    /// themselves, so this is how the next release finds out that it has to
+    /// wake someone up.
+    pub(crate) fn lock_contended(&self) {
+        while let Err(val) = self.mark_contended() {
+            waited(futex_wait::<S>(&self.futex, val), None);
+        }
+    }
+
+    /// Marks the lock as contended, taking it if it was free.
+    ///
+    /// This is a single step, so a concurrent release can't slip in between.
+    /// Returns the value to wait for if it wasn't free.
+    #[inline]
+    fn mark_contended(&self) -> Result<(), i32> {
+        let mut val = self.futex.load(Ordering::Relaxed);
+        loop {
+            let new = (val & POISONED) | CONTENDED;
+            if val == new {
+                return Err(new);
+            }
+            match self.futex.compare_exchange(val, new, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) if val & M_STATE == UNLOCKED => return Ok(()),
+                Ok(_) => return Err(new),
+                Err(x) => val = x,
+            }
+        }
+    }
+
+    /// Releases the lock if it's contended or poisoned.
+    #[inline(never)]
+    fn unlock_slow(&self) {
+        let val = self.futex.fetch_and(POISONED, Ordering::Release) & M_STATE;
+        debug_assert!(val != UNLOCKED, "unlocked a mutex that wasn't locked");
+        if val == CONTENDED {
+            // wake up just one of them: it marks the lock as contended again
+            // (see lock_contended), so the next release takes care of the rest
+            let _n = woken(futex_wake::<S>(&self.futex, 1));
+            #[cfg(test)]
+            WOKEN.with(|woken| woken.set(woken.get() + _n));
+        }
+    }
+
+    /// Spins for a while in the hope that the lock is released soon.
+    ///
+    /// Like glibc, we spin at most about twice as long as it took lately
+    /// and then update that estimate with how long it took this time.
+    /// Returns `true` if we got the lock.
+    #[inline(never)]
+    fn spin(&self) -> bool {
+        let spins = self.spins.load(Ordering::Relaxed);
+        let max = cmp::min(self.max_spins, spins.saturating_mul(2).saturating_add(10));
+        let mut count = 0;
+        let acquired = loop {
+            if count >= max {
+                break false;
+            }
+            count += 1;
+            hint::spin_loop();
+            // only try to take it when it looks free so we don't steal the cache line from the owner
+            if self.futex.load(Ordering::Relaxed) & M_STATE == UNLOCKED && self.try_lock().is_some() {
+                break true;
+            }
+        };
+        self.spins.store(spins + (count - spins) / 8, Ordering::Relaxed);
+        acquired
+    }
+}
+
+impl Futex<Shared> {
+    /// Initializes an unlocked instance in place and returns a reference to it.
+    ///
+    /// This is for memory that is shared with other processes (see the `shared` module).
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be valid for writes of `mem::size_of::<Futex<Shared>>()` bytes
+    /// (it keeps more than the futex word) and aligned to `mem::align_of::<Futex<Shared>>()`.
+    /// It has to stay mapped for `'a` and nobody may be using the lock while it's initialized.
+    pub unsafe fn init_at<'a>(ptr: *mut Futex<Shared>) -> &'a Futex<Shared> {
+        ptr::write(ptr, Futex::new());
+        &*ptr
+    }
+
+    /// Returns a reference to an instance that was initialized elsewhere.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to an instance initialized with `init_at` (possibly
+    /// by another process) that stays mapped for `'a`.
+    pub unsafe fn from_ptr<'a>(ptr: *const Futex<Shared>) -> &'a Futex<Shared> {
+        &*ptr
+    }
 }
 
-impl Mutex for Futex {
+impl<S: Scope> Mutex for Futex<S> {
     type LockState = ();
 
-    // TOOD: review memory orderings
     /// Acquires the lock.
     ///
     /// This blocks until the lock is ours.
     fn lock(&self) {
-        loop {
-            match self.futex.fetch_sub(1, Ordering::Acquire) {
-                1 => return, // jobs done - we got the lock
-                _ => {
-                    // lock is contended :(
-                    self.futex.store(-1, Ordering::Relaxed);
-                    // FIXME: deadlock if release stores the 1, we overwrite with -1 here
-                    //        but they wake us up before we wait
-                    match futex_wait(&self.futex, -1) {
-                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
-                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
-                        Ok(_) => (),
-                        _ => unreachable!(),
-                    }
-                }
-            }
+        if self.try_lock().is_some() || (self.max_spins > 0 && self.spin()) {
+            return;
         }
+
+        // lock is contended :(
+        self.lock_contended();
     }
 
     /// Attempts to acquire the lock without blocking.
-    ///
-    /// Returns `true` if the lock was acquired, `false` otherwise.
     fn try_lock(&self) -> Option<()> {
-        if self.futex.compare_and_swap(1, 0, Ordering::Acquire) == 1 {
-            Some(())
-        } else {
-            None
+        match self.futex.compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed) {
+            Ok(_) => Some(()),
+            Err(POISONED) => self.futex.compare_exchange(POISONED, POISONED | LOCKED, Ordering::Acquire, Ordering::Relaxed)
+                .ok().map(|_| ()),
+            Err(_) => None,
         }
     }
 
     /// Releases the lock.
     fn unlock(&self, _: ()) {
-        match self.futex.fetch_add(1, Ordering::Release) {
-            0 => return, // jobs done - no waiters
-            _ => {
-                // wake them up
-                self.futex.store(1, Ordering::Release);
-                futex_wake(&self.futex, i32::MAX).unwrap();
+        if self.futex.compare_exchange(LOCKED, UNLOCKED, Ordering::Release, Ordering::Relaxed).is_err() {
+            self.unlock_slow();
+        }
+    }
+}
+
+impl<S: Scope> TimedMutex for Futex<S> {
+    /// Attempts to acquire the lock, blocking until `deadline` at most.
+    ///
+    /// Timing out leaves the lock marked as contended as there may
+    /// still be other waiters relying on the release to wake them up.
+    /// At worst, this costs the next release a spurious wake.
+    fn try_lock_until(&self, deadline: Instant) -> Option<()> {
+        if self.try_lock().is_some() || (self.max_spins > 0 && self.spin()) {
+            return Some(());
+        }
+
+        // see lock_contended
+        while let Err(val) = self.mark_contended() {
+            if !waited(futex_wait_until::<S>(&self.futex, val, deadline), Some(deadline)) {
+                return None;
             }
         }
+        // got it (marked as contended but that's fine)
+        Some(())
+    }
+}
+
+impl<S: Scope> PoisonableLock for Futex<S> {
+    /// Marks the lock as poisoned.
+    fn poison(&self) {
+        self.futex.fetch_or(POISONED, Ordering::Relaxed);
+    }
+
+    /// Whether the lock is poisoned.
+    fn is_poisoned(&self) -> bool {
+        self.futex.load(Ordering::Relaxed) & POISONED != 0
+    }
+
+    /// Clears the poisoned state.
+    fn clear_poison(&self) {
+        self.futex.fetch_and(!POISONED, Ordering::Relaxed);
     }
 }
 
-impl Default for Futex {
+impl<S: Scope> Default for Futex<S> {
     /// Creates a new instance.
-    fn default() -> Futex {
-        Futex { futex: AtomicI32::new(1) }
+    fn default() -> Futex<S> {
+        Futex::with_max_spins(DEFAULT_MAX_SPINS)
     }
 }
 
-impl Debug for Futex {
+impl<S: Scope> Debug for Futex<S> {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
         write!(f, "Futex@{:p} (={})", &self.futex as *const _, self.futex.load(Ordering::SeqCst))
     }
@@ -1,34 +1,292 @@
+mod fair_mutex;
 mod futex;
+mod pi_mutex;
+mod robust_mutex;
 // legacy versions:
 //mod rwfutex;
 //mod rwfutex2;
 //mod rwfutex3;
+#[cfg(target_has_atomic = "64")]
 mod rwfutex4;
 
-pub use self::futex::Futex as Mutex;
-pub use self::rwfutex4::RwFutex2 as RwLock;
+pub use self::fair_mutex::{FairMutex, Fairness};
+pub use self::futex::{Futex, DEFAULT_MAX_SPINS};
+pub use self::pi_mutex::PiMutex;
+pub use self::robust_mutex::RobustMutex;
+#[cfg(target_has_atomic = "64")]
+pub use self::rwfutex4::{PhaseFair, Policy, ReaderPreferring, RwFutex2, WriterPreferring};
+pub use sys::{Private, Scope, Shared};
+
+/// A mutex for use within the process.
+pub type Mutex = Futex<Private>;
+/// A mutex that also works across processes in shared memory.
+pub type SharedMutex = Futex<Shared>;
+/// A reader-writer lock for use within the process (see `Policy` for `P`).
+#[cfg(target_has_atomic = "64")]
+pub type RwLock<P = WriterPreferring> = RwFutex2<Private, P>;
+/// A reader-writer lock that also works across processes in shared memory (see `Policy` for `P`).
+#[cfg(target_has_atomic = "64")]
+pub type SharedRwLock<P = WriterPreferring> = RwFutex2<Shared, P>;
 
 #[cfg(test)]
 mod tests {
-    use std::thread;
+    // (the reader-writer lock tests need 64-bit atomics)
+    #![cfg_attr(not(target_has_atomic = "64"), allow(unused_imports))]
+    use std::{mem, thread};
+    use std::cell::UnsafeCell;
     use std::time::Duration;
     use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
     use super::*;
+    use {RawDowngradableRwLock, RawMutex, RawPoisonableLock, RawRwLock, RawTimedMutex, RawTimedRwLock, RawTryRwLock, RawUpgradableRwLock};
 
     #[test]
     fn mutex() {
         let futex = Arc::new(Mutex::new());
         let futex2 = futex.clone();
-        futex.acquire();
+        futex.lock();
         thread::spawn(move || {
             thread::sleep(Duration::from_millis(100));
-            futex2.release();
+            futex2.unlock(());
         }).join().unwrap();
-        futex.acquire();
-        futex.release();
+        futex.lock();
+        futex.unlock(());
+    }
+
+    #[test]
+    fn mutex_spin() {
+        for &max_spins in &[0, DEFAULT_MAX_SPINS] {
+            let shared = Arc::new((Mutex::with_max_spins(max_spins), AtomicUsize::new(0)));
+            let threads: Vec<_> = (0..4).map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    let (ref futex, ref count) = *shared;
+                    for _ in 0..10000 {
+                        futex.lock();
+                        // not atomic on purpose: the lock has to make it so
+                        count.store(count.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+                        futex.unlock(());
+                    }
+                })
+            }).collect();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            assert_eq!(shared.1.load(Ordering::Relaxed), 40000);
+        }
+    }
+
+    #[test]
+    fn mutex_stress() {
+        // no spinning so everyone goes through the sleeping paths
+        let shared = Arc::new((Mutex::with_max_spins(0), AtomicUsize::new(0)));
+        let threads: Vec<_> = (0..16).map(|i| {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                let (ref futex, ref count) = *shared;
+                for j in 0..20000 {
+                    if (i + j) % 3 == 0 {
+                        while futex.try_lock_for(Duration::from_micros(10)).is_none() {}
+                    } else {
+                        futex.lock();
+                    }
+                    count.store(count.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+                    if j % 100 == 0 {
+                        // let the others pile up
+                        thread::yield_now();
+                    }
+                    futex.unlock(());
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(shared.1.load(Ordering::Relaxed), 16 * 20000);
+    }
+
+    #[test]
+    fn mutex_poisoned() {
+        // the flag mustn't get in the way of locking, nor get lost by it
+        let shared = Arc::new((Mutex::with_max_spins(0), AtomicUsize::new(0)));
+        shared.0.poison();
+        let threads: Vec<_> = (0..4).map(|_| {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                let (ref futex, ref count) = *shared;
+                for j in 0..1000 {
+                    futex.lock();
+                    count.store(count.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+                    if j % 10 == 0 {
+                        thread::yield_now();
+                    }
+                    futex.unlock(());
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(shared.1.load(Ordering::Relaxed), 4000);
+
+        let futex = &shared.0;
+        assert!(futex.is_poisoned());
+        assert!(futex.try_lock().is_some());
+        assert!(futex.try_lock().is_none());
+        futex.unlock(());
+        futex.clear_poison();
+        assert!(!futex.is_poisoned());
+        assert!(futex.try_lock().is_some());
+        futex.unlock(());
+    }
+
+    #[test]
+    #[cfg(target_has_atomic = "64")]
+    fn rwlock_poisoned() {
+        let rwlock = RwLock::new();
+        rwlock.poison();
+        rwlock.acquire_write();
+        rwlock.release_write(());
+        rwlock.acquire_read();
+        rwlock.release_read(());
+        assert!(rwlock.is_poisoned());
+        rwlock.clear_poison();
+        assert!(!rwlock.is_poisoned());
     }
 
     #[test]
+    fn fair_mutex() {
+        let shared = Arc::new((FairMutex::<Private>::new(), Mutex::new(), AtomicUsize::new(0)));
+        shared.0.lock();
+
+        // queue up two waiters, one after the other
+        let threads: Vec<_> = (1..3).map(|i| {
+            let shared = shared.clone();
+            let thread = thread::spawn(move || {
+                let (ref fair, _, ref order) = *shared;
+                fair.lock();
+                order.store(order.load(Ordering::Relaxed) * 10 + i, Ordering::Relaxed);
+                fair.unlock(());
+            });
+            thread::sleep(Duration::from_millis(50));
+            thread
+        }).collect();
+
+        // both are asleep, so there's no fighting over it: first come, first served
+        assert_eq!(shared.0.try_lock_for(Duration::from_millis(10)), None);
+        shared.0.unlock(());
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(shared.2.load(Ordering::Relaxed), 12);
+
+        for &fairness in &[Fairness::Strict, Fairness::Eventual] {
+            let shared = Arc::new((FairMutex::<Private>::with_fairness(fairness), AtomicUsize::new(0)));
+            let threads: Vec<_> = (0..4).map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    let (ref fair, ref count) = *shared;
+                    for _ in 0..10000 {
+                        fair.lock();
+                        count.store(count.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+                        fair.unlock(());
+                    }
+                })
+            }).collect();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            assert_eq!(shared.1.load(Ordering::Relaxed), 40000);
+        }
+    }
+
+    #[test]
+    fn fair_mutex_handoff() {
+        let shared = Arc::new((FairMutex::<Private>::new(), AtomicUsize::new(0)));
+        shared.0.lock();
+
+        // three waiters go to sleep one after another...
+        let threads: Vec<_> = (1..4).map(|i| {
+            let shared = shared.clone();
+            let thread = thread::spawn(move || {
+                let (ref fair, ref order) = *shared;
+                fair.lock();
+                order.store(order.load(Ordering::Relaxed) * 10 + i, Ordering::Relaxed);
+                fair.unlock(());
+            });
+            thread::sleep(Duration::from_millis(50));
+            thread
+        }).collect();
+
+        // ...and get the lock in that order even though we ask for it again right away
+        shared.0.unlock(());
+        shared.0.lock();
+        shared.1.store(shared.1.load(Ordering::Relaxed) * 10 + 4, Ordering::Relaxed);
+        shared.0.unlock(());
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(shared.1.load(Ordering::Relaxed), 1234);
+        assert!(format!("{:?}", shared.0).ends_with("(=0x00000000)"));
+    }
+
+    #[test]
+    fn mutex_wake_one() {
+        let futex = Arc::new(Mutex::with_max_spins(0));
+        futex.lock();
+        let threads: Vec<_> = (0..8).map(|_| {
+            let futex = futex.clone();
+            thread::spawn(move || {
+                futex.lock();
+                futex.unlock(());
+                woken()
+            })
+        }).collect();
+        // let them all fall asleep
+        thread::sleep(Duration::from_millis(100));
+
+        futex.unlock(());
+        // no thundering herd: every release wakes up one of them at most
+        // (and the ones that got woken up make sure the rest aren't forgotten)
+        let mut total = woken();
+        assert_eq!(total, 1);
+        for thread in threads {
+            let woken = thread.join().unwrap();
+            assert!(woken <= 1);
+            total += woken;
+        }
+        assert!(total <= 8);
+    }
+
+    /// Returns how many waiters the releases on the current thread woke up so far.
+    fn woken() -> i32 {
+        super::futex::WOKEN.with(|woken| woken.get())
+    }
+
+    #[test]
+    fn mutex_timeout() {
+        let futex = Arc::new(Mutex::new());
+        futex.lock();
+
+        let futex2 = futex.clone();
+        let waiter = thread::spawn(move || {
+            futex2.lock();
+            futex2.unlock(());
+        });
+        thread::sleep(Duration::from_millis(50));
+
+        assert_eq!(futex.try_lock_for(Duration::from_millis(50)), None);
+
+        // the waiter must not be lost because of the timeout
+        futex.unlock(());
+        waiter.join().unwrap();
+
+        assert_eq!(futex.try_lock_for(Duration::from_millis(50)), Some(()));
+        futex.unlock(());
+    }
+
+    #[test]
+    #[cfg(target_has_atomic = "64")]
     fn rwlock() {
         let futex = Arc::new(RwLock::new());
         let futex2 = futex.clone();
@@ -37,15 +295,429 @@ mod tests {
         futex.acquire_read();
         thread::spawn(move || {
             futex2.acquire_read();
-            futex2.release_read();
+            futex2.release_read(());
             futex2.acquire_write();
             thread::sleep(Duration::from_millis(100));
-            futex2.release_write();
+            futex2.release_write(());
+        });
+        futex.release_read(());
+        futex.release_read(());
+        futex.release_read(());
+        futex.acquire_read();
+        futex.release_read(());
+    }
+
+    #[test]
+    #[cfg(target_has_atomic = "64")]
+    fn rwlock_timeout() {
+        let timeout = Duration::from_millis(50);
+        let futex = Arc::new(RwLock::new());
+
+        futex.acquire_write();
+        assert_eq!(futex.try_acquire_read_for(timeout), None);
+        futex.release_write(());
+
+        futex.acquire_read();
+        assert_eq!(futex.try_acquire_write_for(timeout), None);
+        // the timed out writer must not keep readers out
+        assert_eq!(futex.try_acquire_read_for(timeout), Some(()));
+        futex.release_read(());
+
+        // a timed out owner must hand over to the next writer
+        let futex2 = futex.clone();
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            futex2.acquire_write();
+            futex2.release_write(());
         });
-        futex.release_read();
-        futex.release_read();
-        futex.release_read();
+        assert_eq!(futex.try_acquire_write_for(timeout), None);
+        futex.release_read(());
+        writer.join().unwrap();
+
+        // and nothing is left over
+        futex.acquire_write();
+        futex.release_write(());
         futex.acquire_read();
-        futex.release_read();
+        futex.release_read(());
+    }
+
+    /// Checks that nobody holds `futex` and nobody is left waiting for it.
+    #[cfg(target_has_atomic = "64")]
+    fn assert_idle<P: Policy>(futex: &RwLock<P>) {
+        assert_eq!(futex.try_acquire_write(), Some(()));
+        futex.release_write(());
+        assert_eq!(futex.try_acquire_read(), Some(()));
+        assert_eq!(futex.try_acquire_read(), Some(()));
+        assert_eq!(futex.try_acquire_write(), None);
+        futex.release_read(());
+        futex.release_read(());
+        assert_eq!(futex.try_acquire_write(), Some(()));
+        futex.release_write(());
+    }
+
+    #[test]
+    #[cfg(target_has_atomic = "64")]
+    fn rwlock_try() {
+        let futex = RwLock::new();
+
+        assert_eq!(futex.try_acquire_write(), Some(()));
+        assert_eq!(futex.try_acquire_read(), None);
+        assert_eq!(futex.try_acquire_write(), None);
+        futex.release_write(());
+
+        assert_eq!(futex.try_acquire_read(), Some(()));
+        assert_eq!(futex.try_acquire_read(), Some(()));
+        assert_eq!(futex.try_acquire_write(), None);
+        futex.release_read(());
+        futex.release_read(());
+
+        // failed attempts leave no trace
+        assert_idle(&futex);
+    }
+
+    #[test]
+    #[cfg(target_has_atomic = "64")]
+    fn rwlock_many_readers() {
+        // way past what fits into a 32-bit word
+        const READERS: usize = 1 << 20;
+        let futex = Arc::new(RwLock::new());
+        for _ in 0..READERS {
+            futex.acquire_read();
+        }
+
+        let futex2 = futex.clone();
+        let writer = thread::spawn(move || {
+            futex2.acquire_write();
+            futex2.release_write(());
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(futex.try_acquire_read(), None);
+
+        for _ in 0..READERS {
+            futex.release_read(());
+        }
+        writer.join().unwrap();
+        assert_idle(&futex);
+    }
+
+    #[test]
+    #[cfg(target_has_atomic = "64")]
+    fn rwlock_overflow() {
+        let futex = Arc::new(RwLock::with_readers(super::rwfutex4::MAX_READERS));
+        assert_eq!(futex.try_acquire_read(), None);
+        assert_eq!(futex.try_acquire_read_for(Duration::from_millis(10)), None);
+
+        // blocking readers wait for a slot
+        let futex2 = futex.clone();
+        let reader = thread::spawn(move || {
+            futex2.acquire_read();
+            futex2.release_read(());
+        });
+        thread::sleep(Duration::from_millis(50));
+        futex.release_read(());
+        reader.join().unwrap();
+
+        // nothing broke
+        assert_eq!(futex.try_acquire_read(), Some(()));
+        assert_eq!(futex.try_acquire_read(), None);
+        futex.release_read(());
+        futex.release_read(());
+        assert_eq!(futex.try_acquire_write(), None);
+    }
+
+    #[test]
+    #[cfg(target_has_atomic = "64")]
+    fn rwlock_upgradable() {
+        let futex = Arc::new(RwLock::new());
+
+        // readers are welcome but writers aren't
+        futex.acquire_upgradable();
+        assert_eq!(futex.try_acquire_read(), Some(()));
+        assert_eq!(futex.try_acquire_write(), None);
+        assert_eq!(futex.try_upgrade(()), Err(()));
+        futex.release_read(());
+        assert_eq!(futex.try_upgrade(()), Ok(()));
+        assert_eq!(futex.try_acquire_read(), None);
+        futex.release_write(());
+
+        // a writer and another upgradable reader queue up behind us
+        // but we get to write first
+        let shared = Arc::new((RwLock::new(), AtomicUsize::new(0)));
+        shared.0.acquire_upgradable();
+        shared.0.acquire_read();
+        let threads: Vec<_> = (0..2).map(|i| {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                let (ref futex, ref value) = *shared;
+                if i == 0 {
+                    futex.acquire_write();
+                    value.store(value.load(Ordering::Relaxed) * 10 + 2, Ordering::Relaxed);
+                    futex.release_write(());
+                } else {
+                    futex.acquire_upgradable();
+                    futex.upgrade(());
+                    value.store(value.load(Ordering::Relaxed) * 10 + 3, Ordering::Relaxed);
+                    futex.release_write(());
+                }
+            })
+        }).collect();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(shared.1.load(Ordering::Relaxed), 0);
+
+        // the other reader leaves while we're waiting to upgrade
+        let shared2 = shared.clone();
+        let reader = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            shared2.0.release_read(());
+        });
+        shared.0.upgrade(());
+        reader.join().unwrap();
+        shared.1.store(1, Ordering::Relaxed);
+        shared.0.release_write(());
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let order = shared.1.load(Ordering::Relaxed);
+        assert!(order == 123 || order == 132, "{}", order);
+
+        // downgrading lets the next upgradable reader in
+        futex.acquire_upgradable();
+        futex.downgrade_upgradable(());
+        futex.acquire_upgradable();
+        futex.release_upgradable(());
+        futex.release_read(());
+        assert_idle(&futex);
+    }
+
+    #[test]
+    #[cfg(target_has_atomic = "64")]
+    fn rwlock_downgrade() {
+        let shared = Arc::new((RwLock::new(), AtomicUsize::new(0)));
+        shared.0.acquire_write();
+
+        let shared2 = shared.clone();
+        let reader = thread::spawn(move || {
+            let (ref futex, ref value) = *shared2;
+            futex.acquire_read();
+            let seen = value.load(Ordering::Relaxed);
+            futex.release_read(());
+            seen
+        });
+        thread::sleep(Duration::from_millis(50));
+
+        // the queued reader gets in while we're still reading
+        shared.1.store(1, Ordering::Relaxed);
+        shared.0.downgrade(());
+        assert_eq!(reader.join().unwrap(), 1);
+        assert_eq!(shared.0.try_acquire_write(), None);
+        shared.0.release_read(());
+
+        // a waiting writer is next in line once we're done reading
+        shared.0.acquire_write();
+        let shared2 = shared.clone();
+        let writer = thread::spawn(move || {
+            shared2.0.acquire_write();
+            shared2.0.release_write(());
+        });
+        thread::sleep(Duration::from_millis(50));
+        shared.0.downgrade(());
+        assert_eq!(shared.0.try_acquire_read(), None);
+        shared.0.release_read(());
+        writer.join().unwrap();
+        assert_idle(&shared.0);
+    }
+
+    #[test]
+    #[cfg(target_has_atomic = "64")]
+    fn rwlock_handoff() {
+        let shared = Arc::new((RwLock::new(), AtomicUsize::new(0)));
+        shared.0.acquire_write();
+
+        // three writers go to sleep one after another...
+        let writers: Vec<_> = (1..4).map(|i| {
+            let shared = shared.clone();
+            let writer = thread::spawn(move || {
+                let (ref futex, ref value) = *shared;
+                futex.acquire_write();
+                value.store(value.load(Ordering::Relaxed) * 10 + i, Ordering::Relaxed);
+                futex.release_write(());
+            });
+            thread::sleep(Duration::from_millis(50));
+            writer
+        }).collect();
+
+        // ...and get the lock in that order even though we ask for it again right away
+        shared.0.release_write(());
+        shared.0.acquire_write();
+        shared.1.store(shared.1.load(Ordering::Relaxed) * 10 + 4, Ordering::Relaxed);
+        shared.0.release_write(());
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(shared.1.load(Ordering::Relaxed), 1234);
+        assert_idle(&shared.0);
+    }
+
+    /// Keeps `futex` busy with four threads that grab it over and over until `stop` is set.
+    #[cfg(target_has_atomic = "64")]
+    fn hog<P: Policy>(futex: &Arc<RwLock<P>>, stop: &Arc<AtomicBool>, write: bool) -> Vec<thread::JoinHandle<()>> {
+        (0..4).map(|_| {
+            let (futex, stop) = (futex.clone(), stop.clone());
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    if write {
+                        futex.acquire_write();
+                        thread::sleep(Duration::from_millis(1));
+                        futex.release_write(());
+                    } else {
+                        futex.acquire_read();
+                        thread::sleep(Duration::from_millis(1));
+                        futex.release_read(());
+                    }
+                }
+            })
+        }).collect()
+    }
+
+    #[test]
+    #[cfg(target_has_atomic = "64")]
+    fn rwlock_policies() {
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut threads = Vec::new();
+
+        // readers that keep overlapping don't starve a writer...
+        let futex = Arc::new(RwLock::new());
+        threads.extend(hog(&futex, &stop, false));
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(futex.try_acquire_write_for(Duration::from_secs(1)), Some(()));
+        futex.release_write(());
+
+        // ...and writers that keep coming don't starve a reader
+        let futex = Arc::new(RwLock::with_policy(ReaderPreferring));
+        threads.extend(hog(&futex, &stop, true));
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(futex.try_acquire_read_for(Duration::from_secs(1)), Some(()));
+        futex.release_read(());
+
+        // phase-fair does both
+        let futex = Arc::new(RwLock::with_policy(PhaseFair));
+        threads.extend(hog(&futex, &stop, false));
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(futex.try_acquire_write_for(Duration::from_secs(1)), Some(()));
+        futex.release_write(());
+        let futex = Arc::new(RwLock::with_policy(PhaseFair));
+        threads.extend(hog(&futex, &stop, true));
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(futex.try_acquire_read_for(Duration::from_secs(1)), Some(()));
+        futex.release_read(());
+
+        stop.store(true, Ordering::Relaxed);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn pi_mutex() {
+        let futex: Arc<PiMutex> = Arc::new(PiMutex::new());
+        futex.lock();
+
+        let futex2 = futex.clone();
+        let waiter = thread::spawn(move || {
+            assert_eq!(futex2.try_lock(), None);
+            assert_eq!(futex2.try_lock_for(Duration::from_millis(50)), None);
+            futex2.lock();
+            futex2.unlock(());
+        });
+        thread::sleep(Duration::from_millis(100));
+        // there's a waiter queued in the kernel now
+        futex.unlock(());
+        waiter.join().unwrap();
+
+        assert_eq!(futex.try_lock(), Some(()));
+        futex.unlock(());
+    }
+
+    #[test]
+    fn robust_mutex() {
+        // (glibc has a robust list for every thread already)
+        unsafe { RobustMutex::share_libc_list() };
+        let futex = Arc::new(RobustMutex::new());
+        let futex2 = futex.clone();
+        thread::spawn(move || {
+            // die while holding it
+            futex2.lock().unwrap();
+        }).join().unwrap();
+
+        assert!(futex.try_lock().unwrap().is_err());
+        futex.unlock();
+
+        // recovered
+        assert!(futex.lock().is_ok());
+        futex.unlock();
+    }
+
+    struct PthreadMutex(UnsafeCell<libc::pthread_mutex_t>);
+
+    unsafe impl Send for PthreadMutex {}
+    unsafe impl Sync for PthreadMutex {}
+
+    impl PthreadMutex {
+        fn robust() -> Arc<PthreadMutex> {
+            let mutex = Arc::new(PthreadMutex(UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER)));
+            unsafe {
+                let mut attr = mem::zeroed();
+                libc::pthread_mutexattr_init(&mut attr);
+                libc::pthread_mutexattr_setrobust(&mut attr, libc::PTHREAD_MUTEX_ROBUST);
+                assert_eq!(libc::pthread_mutex_init(mutex.0.get(), &attr), 0);
+                libc::pthread_mutexattr_destroy(&mut attr);
+            }
+            mutex
+        }
+
+        fn try_lock(&self) -> i32 {
+            unsafe { libc::pthread_mutex_trylock(self.0.get()) }
+        }
+
+        fn unlock(&self) {
+            unsafe {
+                libc::pthread_mutex_consistent(self.0.get());
+                assert_eq!(libc::pthread_mutex_unlock(self.0.get()), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn robust_mutex_pthread() {
+        unsafe { RobustMutex::share_libc_list() };
+        let a = PthreadMutex::robust();
+        let b = Arc::new(RobustMutex::new());
+        let c = PthreadMutex::robust();
+        let d = Arc::new(RobustMutex::new());
+        let (a2, b2, c2, d2) = (a.clone(), b.clone(), c.clone(), d.clone());
+        thread::spawn(move || {
+            // take them on and off the same list in every order
+            assert_eq!(a2.try_lock(), 0);
+            b2.lock().unwrap();
+            assert_eq!(c2.try_lock(), 0);
+            d2.lock().unwrap();
+            a2.unlock();
+            d2.unlock();
+            assert_eq!(a2.try_lock(), 0);
+            b2.unlock();
+            d2.lock().unwrap();
+            a2.unlock();
+            b2.lock().unwrap();
+            // die while holding b, c and d
+        }).join().unwrap();
+
+        assert_eq!(a.try_lock(), 0);
+        a.unlock();
+        assert!(b.lock().is_err());
+        b.unlock();
+        assert_eq!(c.try_lock(), libc::EOWNERDEAD);
+        c.unlock();
+        assert!(d.lock().is_err());
+        d.unlock();
     }
 }
@@ -0,0 +1,174 @@
+use std::thread;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::{Instant, SystemTime};
+use lock_wrappers::raw::Mutex;
+use sys::{current_tid, futex_lock_pi, futex_lock_pi2, futex_trylock_pi, futex_unlock_pi, Private, Scope};
+use traits::TimedMutex;
+use error::Error;
+
+/// A priority-inheritance mutex.
+///
+/// The futex word holds the TID of the owner (0 if unlocked), which allows
+/// the kernel to boost the priority of the owner while higher-priority threads
+/// are waiting for it. This prevents priority inversion for real-time threads.
+///
+/// This is not designed for direct use but as a building block for locks.
+///
+/// Unlike `Futex`, it must always be released by the thread that acquired it.
+/// Releasing it from any other thread panics.
+/// It's also not reentrant: acquiring it twice on the same thread panics.
+///
+/// If the kernel has no PI futexes (`Error::Unsupported`), waiters spin
+/// instead and there is no priority inheritance.
+pub struct PiMutex<S: Scope = Private> {
+    futex: AtomicU32,
+    scope: PhantomData<S>,
+}
+
+// set once we know that the kernel has no FUTEX_LOCK_PI2 (older than 5.14)
+static NO_LOCK_PI2: AtomicBool = AtomicBool::new(false);
+
+impl<S: Scope> PiMutex<S> {
+    /// Creates a new instance.
+    pub fn new() -> PiMutex<S> {
+        PiMutex::default()
+    }
+
+    #[inline(always)]
+    fn try_lock_fast(&self) -> bool {
+        self.futex.compare_exchange(0, current_tid(), Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    /// Lets the kernel sort it out.
+    ///
+    /// Returns `false` if we gave up because `deadline` passed.
+    #[inline(never)]
+    fn lock_slow(&self, deadline: Option<Instant>) -> bool {
+        loop {
+            let ret = match deadline {
+                Some(deadline) if NO_LOCK_PI2.load(Ordering::Relaxed) => {
+                    // only CLOCK_REALTIME is available, so we have to translate
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    futex_lock_pi::<S>(&self.futex, Some(SystemTime::now() + remaining))
+                }
+                Some(deadline) => futex_lock_pi2::<S>(&self.futex, Some(deadline)),
+                None => futex_lock_pi::<S>(&self.futex, None),
+            };
+
+            match ret {
+                Ok(()) => return true,
+                Err(Error::TimedOut) => return false,
+                Err(Error::Interrupted) => (),
+                // owner changed under our feet (e.g. it just unlocked) - try again
+                Err(Error::WouldBlock) => (),
+                Err(Error::Unsupported) if deadline.is_some() && !NO_LOCK_PI2.load(Ordering::Relaxed) => {
+                    NO_LOCK_PI2.store(true, Ordering::Relaxed);
+                }
+                Err(Error::Unsupported) => return self.lock_spin(deadline),
+                Err(Error::Other(::libc::EDEADLK)) => {
+                    panic!("PiMutex is not reentrant");
+                }
+                Err(e) => panic!("FUTEX_LOCK_PI failed: {}", e),
+            }
+        }
+    }
+
+    /// The fallback for when there are no PI futexes at all
+    /// (e.g. they are disabled in the kernel or blocked by seccomp).
+    ///
+    /// Nobody can sleep on the lock then, so all we can do is spin.
+    /// There's no priority inheritance either, of course.
+    #[cold]
+    fn lock_spin(&self, deadline: Option<Instant>) -> bool {
+        loop {
+            if self.try_lock_fast() {
+                return true;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return false;
+            }
+            thread::yield_now();
+        }
+    }
+}
+
+impl<S: Scope> Mutex for PiMutex<S> {
+    type LockState = ();
+
+    /// Acquires the lock.
+    ///
+    /// This blocks until the lock is ours.
+    fn lock(&self) {
+        if !self.try_lock_fast() {
+            self.lock_slow(None);
+        }
+    }
+
+    /// Attempts to acquire the lock without blocking.
+    fn try_lock(&self) -> Option<()> {
+        if self.try_lock_fast() {
+            return Some(());
+        }
+
+        // the word can be in states we don't handle in userspace
+        // (e.g. a dead owner) - the kernel knows what to do
+        match futex_trylock_pi::<S>(&self.futex) {
+            Ok(()) => Some(()),
+            Err(Error::WouldBlock) => None,
+            Err(Error::Other(::libc::EDEADLK)) => None,
+            // then there's nothing the kernel could know better
+            Err(Error::Unsupported) => None,
+            Err(e) => panic!("FUTEX_TRYLOCK_PI failed: {}", e),
+        }
+    }
+
+    /// Releases the lock.
+    fn unlock(&self, _: ()) {
+        if self.futex.compare_exchange(current_tid(), 0, Ordering::Release, Ordering::Relaxed).is_ok() {
+            // jobs done - no waiters
+            return;
+        }
+
+        // waiters (or not ours) - the kernel hands it over to the next one
+        match futex_unlock_pi::<S>(&self.futex) {
+            Ok(()) => (),
+            // without PI futexes nobody sets the waiters bit, so it's not ours either way
+            Err(Error::Other(::libc::EPERM)) | Err(Error::Unsupported) => {
+                panic!("PiMutex released by a thread that doesn't own it");
+            }
+            Err(e) => panic!("FUTEX_UNLOCK_PI failed: {}", e),
+        }
+    }
+}
+
+impl<S: Scope> TimedMutex for PiMutex<S> {
+    /// Attempts to acquire the lock, blocking until `deadline` at most.
+    ///
+    /// This uses `FUTEX_LOCK_PI2` to wait on `CLOCK_MONOTONIC`, falling back to
+    /// `FUTEX_LOCK_PI` (which only supports `CLOCK_REALTIME`) on older kernels.
+    fn try_lock_until(&self, deadline: Instant) -> Option<()> {
+        if self.try_lock_fast() || self.lock_slow(Some(deadline)) {
+            Some(())
+        } else {
+            None
+        }
+    }
+}
+
+impl<S: Scope> Default for PiMutex<S> {
+    /// Creates a new instance.
+    fn default() -> PiMutex<S> {
+        PiMutex {
+            futex: AtomicU32::new(0),
+            scope: PhantomData,
+        }
+    }
+}
+
+impl<S: Scope> Debug for PiMutex<S> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "PiMutex@{:p} (=0x{:08x})", &self.futex as *const _, self.futex.load(Ordering::SeqCst))
+    }
+}
@@ -0,0 +1,355 @@
+use std::{mem, ptr};
+use std::cell::Cell;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use libc::{c_long, syscall, SYS_get_robust_list, SYS_set_robust_list};
+use sys::{current_tid, futex_wait_bitset, futex_wake_bitset, woken, Shared};
+use error::OwnerDiedError;
+
+const FUTEX_WAITERS: u32    = 0x80000000;
+const FUTEX_OWNER_DIED: u32 = 0x40000000;
+const FUTEX_TID_MASK: u32   = 0x3fffffff;
+
+const ID_ANY: i32 = -1;
+
+/// `struct robust_list`
+#[repr(C)]
+struct ListEntry {
+    next: Cell<*const ListEntry>,
+}
+
+/// `struct robust_list_head`
+#[repr(C)]
+struct ListHead {
+    list: ListEntry,
+    futex_offset: c_long,
+    list_op_pending: Cell<*const ListEntry>,
+}
+
+// how many pointers a RobustMutex has room for (see `links`)
+const LINKS: usize = 7;
+
+// Whether the robust mutexes of libc keep a pointer to their predecessor right before
+// their entry, which we have to keep up to date when we share its list (see `adopt`).
+// It's unknown for any other libc, so we don't touch its list there.
+#[cfg(any(target_env = "musl", all(target_env = "gnu", any(target_pointer_width = "64", target_arch = "x86_64"))))]
+const LIBC_LINKS_BACK: Option<bool> = Some(true);
+#[cfg(all(target_env = "gnu", target_pointer_width = "32", not(target_arch = "x86_64")))]
+const LIBC_LINKS_BACK: Option<bool> = Some(false);
+#[cfg(not(any(target_env = "musl", target_env = "gnu")))]
+const LIBC_LINKS_BACK: Option<bool> = None;
+
+/// The robust list of a thread and where our locks keep their entry for it.
+#[derive(Clone, Copy)]
+struct RobustList {
+    head: *const ListHead,
+    // index into `RobustMutex::links`
+    slot: usize,
+    // whether the entry before ours in `links` points back to the predecessor
+    links_back: bool,
+}
+
+impl RobustList {
+    fn head(&self) -> &ListHead {
+        // (the head lives as long as the thread)
+        unsafe { &*self.head }
+    }
+}
+
+thread_local!(static OWN_LIST: ListHead = const { ListHead {
+    list: ListEntry { next: Cell::new(ptr::null()) },
+    futex_offset: mem::offset_of!(RobustMutex, futex) as c_long - mem::offset_of!(RobustMutex, links) as c_long,
+    list_op_pending: Cell::new(ptr::null()),
+} });
+
+thread_local!(static ROBUST_LIST: Cell<Option<RobustList>> = const { Cell::new(None) });
+
+// whether we may put our locks on the robust list of libc (see `RobustMutex::share_libc_list`)
+static SHARE_LIBC_LIST: AtomicBool = AtomicBool::new(false);
+
+/// Runs `f` with the robust list of this thread, setting it up first if necessary.
+fn with_robust_list<R, F: FnOnce(RobustList) -> R>(f: F) -> R {
+    let list = ROBUST_LIST.with(|list| {
+        if list.get().is_none() {
+            list.set(Some(register()));
+        }
+        list.get().unwrap()
+    });
+    f(list)
+}
+
+/// Finds the robust list of this thread, registering our own if there is none yet.
+fn register() -> RobustList {
+    OWN_LIST.with(|own| {
+        // empty lists point back at themselves
+        own.list.next.set(&own.list);
+        let own = RobustList {
+            head: own,
+            slot: 0,
+            links_back: false,
+        };
+
+        let mut head: *const ListHead = ptr::null();
+        let mut len: usize = 0;
+        let ret = unsafe { syscall(SYS_get_robust_list, 0, &mut head, &mut len) };
+        if ret != 0 || head.is_null() {
+            // if this fails the locks still work, they just aren't robust
+            unsafe { syscall(SYS_set_robust_list, own.head, mem::size_of::<ListHead>()) };
+            own
+        } else if SHARE_LIBC_LIST.load(Ordering::Relaxed) {
+            // Replacing it would break the robust pthread mutexes of libc, so we share it.
+            // If we can't, the locks still work, they just aren't robust on this thread.
+            unsafe { adopt(head) }.unwrap_or(own)
+        } else {
+            // (same but we weren't allowed to)
+            own
+        }
+    })
+}
+
+/// Works out how to put our locks on a robust list registered by libc.
+///
+/// There's only one `futex_offset` per list, so our entry has to be where libc's
+/// mutexes have theirs relative to the futex word, which is what `links` is for.
+/// Like libc, we add entries at the front and whenever the entry after ours
+/// belongs to libc, it points back to ours (or the head) from right before itself.
+/// Returns `None` if we don't know how libc does it or our entry doesn't fit.
+unsafe fn adopt(head: *const ListHead) -> Option<RobustList> {
+    let links_back = LIBC_LINKS_BACK?;
+    let ptr_size = mem::size_of::<usize>() as isize;
+    let offset = mem::offset_of!(RobustMutex, futex) as isize - (*head).futex_offset as isize
+        - mem::offset_of!(RobustMutex, links) as isize;
+    let first = if links_back { 1 } else { 0 };
+    if offset % ptr_size != 0 || offset / ptr_size < first || offset / ptr_size >= LINKS as isize {
+        return None;
+    }
+    Some(RobustList {
+        head,
+        slot: (offset / ptr_size) as usize,
+        links_back,
+    })
+}
+
+/// Follows a link in a robust list, which libc may have tagged.
+fn untag(entry: *const ListEntry) -> *const ListEntry {
+    // (glibc marks PI mutexes in the lowest bit)
+    (entry as usize & !1) as *const ListEntry
+}
+
+/// A robust mutual exclusion lock (mutex).
+///
+/// The futex word holds the TID of the owner. Every thread keeps a list of the
+/// robust locks it is holding and registers that list with the kernel.
+/// When a thread dies while holding a lock, the kernel marks it as `FUTEX_OWNER_DIED`
+/// and wakes up a waiter. The next acquisition then succeeds but reports
+/// the death of the previous owner so the protected data can be repaired.
+/// There is no separate "mark consistent" step: releasing the lock is enough.
+///
+/// This is mostly useful for locks in memory that is shared between processes.
+/// The futex is always `Shared` as that's how the kernel treats it when the owner dies.
+///
+/// Like `PiMutex`, it must always be released by the thread that acquired it
+/// and it's not reentrant. Where futexes are unavailable, waiters spin
+/// (see `Futex`), but the kernel still takes care of dead owners.
+///
+/// There is only one robust list per thread, which the locks register on their
+/// own if there is none yet. If libc registered one already (glibc does for every
+/// thread), the locks still work but they aren't robust on that thread, unless
+/// they may share that list with the robust pthread mutexes (see `share_libc_list`).
+/// musl only registers its list when a robust pthread mutex is first locked,
+/// which makes these locks lose their robustness on threads that use one afterwards.
+#[repr(C)]
+pub struct RobustMutex {
+    futex: AtomicU32,
+    // room for our entry in the robust list of the owner (and the link back from it),
+    // which goes wherever the futex_offset of that list says
+    links: [ListEntry; LINKS],
+}
+
+// the list entry is only ever touched by the owner
+unsafe impl Send for RobustMutex {}
+unsafe impl Sync for RobustMutex {}
+
+impl RobustMutex {
+    /// Creates a new instance.
+    pub fn new() -> RobustMutex {
+        RobustMutex::default()
+    }
+
+    /// Lets the locks go on the robust list that libc registered for a thread.
+    ///
+    /// The kernel only knows where the futex word of each entry on a list is from
+    /// the one offset the list was registered with, so the locks put their entry
+    /// where the robust pthread mutexes of libc have theirs. Whenever the next entry
+    /// belongs to libc, they also keep the pointer back to its predecessor that glibc
+    /// and musl keep right before their entries (except 32-bit glibc, which has none).
+    /// On any other libc, this does nothing.
+    ///
+    /// This only affects threads that haven't locked a `RobustMutex` yet.
+    ///
+    /// # Safety
+    ///
+    /// libc has to lay out its robust list like that, which it doesn't promise:
+    /// otherwise, its list (and the robust pthread mutexes on it) gets corrupted.
+    pub unsafe fn share_libc_list() {
+        SHARE_LIBC_LIST.store(true, Ordering::Relaxed);
+    }
+
+    /// Initializes an unlocked instance in place and returns a reference to it.
+    ///
+    /// This is for memory that is shared with other processes (see the `shared` module).
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be valid for writes of `mem::size_of::<RobustMutex>()` bytes
+    /// (it has room for its robust list entries) and aligned to `mem::align_of::<RobustMutex>()`.
+    /// It has to stay mapped for `'a` and nobody may be using the lock while it's initialized.
+    pub unsafe fn init_at<'a>(ptr: *mut RobustMutex) -> &'a RobustMutex {
+        ptr::write(ptr, RobustMutex::new());
+        &*ptr
+    }
+
+    /// Returns a reference to an instance that was initialized elsewhere.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to an instance initialized with `init_at` (possibly
+    /// by another process) that stays mapped for `'a`.
+    pub unsafe fn from_ptr<'a>(ptr: *const RobustMutex) -> &'a RobustMutex {
+        &*ptr
+    }
+
+    /// Attempts to take over the lock given its current (unowned) value.
+    ///
+    /// Returns `None` if we lost a race.
+    #[inline]
+    fn try_take(&self, list: RobustList, val: u32, waited: bool) -> Option<Result<(), OwnerDiedError<()>>> {
+        // if we had to wait, others might still be waiting as well
+        let waiters = if waited { FUTEX_WAITERS } else { val & FUTEX_WAITERS };
+        if self.futex.compare_exchange(val, current_tid() | waiters, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            return None;
+        }
+
+        // put it on our list before the kernel forgets about it
+        let head = list.head();
+        let entry = &self.links[list.slot];
+        let next = head.list.next.get();
+        entry.next.set(next);
+        if list.links_back {
+            self.links[list.slot - 1].next.set(&head.list);
+            let next = untag(next);
+            if !ptr::eq(next, &head.list) {
+                unsafe { (*next.offset(-1)).next.set(entry) };
+            }
+        }
+        head.list.next.set(entry);
+        head.list_op_pending.set(ptr::null());
+
+        if val & FUTEX_OWNER_DIED != 0 {
+            Some(Err(OwnerDiedError::new(())))
+        } else {
+            Some(Ok(()))
+        }
+    }
+
+    /// Acquires the lock.
+    ///
+    /// This blocks until the lock is ours. If the previous owner died
+    /// while holding it, the lock is acquired anyways but an error is returned.
+    pub fn lock(&self) -> Result<(), OwnerDiedError<()>> {
+        with_robust_list(|list| {
+            // in case we die halfway through
+            list.head().list_op_pending.set(&self.links[list.slot]);
+
+            let mut waited = false;
+            loop {
+                let val = self.futex.load(Ordering::Relaxed);
+                if val & FUTEX_TID_MASK == 0 {
+                    // free (or the owner died)
+                    if let Some(ret) = self.try_take(list, val, waited) {
+                        return ret;
+                    }
+                    continue;
+                }
+
+                // make sure the owner knows that it has to wake us up
+                if val & FUTEX_WAITERS == 0
+                    && self.futex.compare_exchange(val, val | FUTEX_WAITERS, Ordering::Relaxed, Ordering::Relaxed).is_err() {
+                    continue;
+                }
+
+                // (our `waited` shadows the one in sys)
+                ::sys::waited(futex_wait_bitset::<Shared>(&self.futex, val | FUTEX_WAITERS, ID_ANY), None);
+                waited = true;
+            }
+        })
+    }
+
+    /// Attempts to acquire the lock without blocking.
+    ///
+    /// Returns `None` if someone else is holding it.
+    pub fn try_lock(&self) -> Option<Result<(), OwnerDiedError<()>>> {
+        with_robust_list(|list| {
+            list.head().list_op_pending.set(&self.links[list.slot]);
+            loop {
+                let val = self.futex.load(Ordering::Relaxed);
+                if val & FUTEX_TID_MASK != 0 {
+                    list.head().list_op_pending.set(ptr::null());
+                    return None;
+                }
+                if let Some(ret) = self.try_take(list, val, false) {
+                    return Some(ret);
+                }
+            }
+        })
+    }
+
+    /// Releases the lock.
+    ///
+    /// Panics if the calling thread isn't holding it.
+    pub fn unlock(&self) {
+        with_robust_list(|list| {
+            let head = list.head();
+            let entry = &self.links[list.slot];
+            head.list_op_pending.set(entry);
+
+            // take it off our list
+            let mut prev = &head.list;
+            loop {
+                let next = untag(prev.next.get());
+                if ptr::eq(next, entry) {
+                    let next = entry.next.get();
+                    prev.next.set(next);
+                    let next = untag(next);
+                    if list.links_back && !ptr::eq(next, &head.list) {
+                        unsafe { (*next.offset(-1)).next.set(prev) };
+                    }
+                    break;
+                }
+                assert!(!ptr::eq(next, &head.list), "RobustMutex released by a thread that doesn't own it");
+                prev = unsafe { &*next };
+            }
+
+            if self.futex.swap(0, Ordering::Release) & FUTEX_WAITERS != 0 {
+                woken(futex_wake_bitset::<Shared>(&self.futex, 1, ID_ANY));
+            }
+            head.list_op_pending.set(ptr::null());
+        })
+    }
+}
+
+impl Default for RobustMutex {
+    /// Creates a new instance.
+    fn default() -> RobustMutex {
+        RobustMutex {
+            futex: AtomicU32::new(0),
+            links: [const { ListEntry { next: Cell::new(ptr::null()) } }; LINKS],
+        }
+    }
+}
+
+impl Debug for RobustMutex {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "RobustMutex@{:p} (=0x{:08x})", &self.futex as *const _, self.futex.load(Ordering::SeqCst))
+    }
+}
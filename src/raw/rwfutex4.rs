@@ -1,9 +1,13 @@
-use std::i32;
-use std::sync::atomic::Ordering;
+use std::{hint, ptr, thread};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::fmt::{Debug, Formatter, Result as FmtResult};
-use sys::{futex_wait_bitset, futex_wake_bitset};
-use integer_atomics::AtomicU32;
+use std::marker::PhantomData;
+use std::time::Instant;
+use sys::{futex_wait_bitset, futex_wait_bitset_until, futex_wake_bitset, monotonic_nanos, waited, woken,
+          Private, Scope, Shared};
+use super::fair_mutex::FAIR_INTERVAL_NS;
 use lock_wrappers::raw::RwLock;
+use traits::{DowngradableRwLock, PoisonableLock, TimedRwLock, TryRwLock, UpgradableRwLock};
 
 #[cfg(feature = "nightly")]
 use std::intrinsics::likely;
@@ -12,6 +16,62 @@ use std::intrinsics::likely;
 #[cfg(not(feature = "nightly"))]
 unsafe fn likely(b: bool) -> bool { b }
 
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// How a `RwFutex2` decides between readers and writers.
+///
+/// This is implemented by `WriterPreferring`, `ReaderPreferring` and `PhaseFair` only.
+pub trait Policy: sealed::Sealed + Send + Sync + 'static {
+    /// Whether readers may come in while a writer waits for the others to leave.
+    #[doc(hidden)]
+    const READERS_FIRST: bool;
+    /// Whether a writer lets the queued readers in even if there are other writers waiting.
+    #[doc(hidden)]
+    const READER_BATCHES: bool;
+}
+
+/// Writers go first: readers queue up as soon as a writer shows up.
+///
+/// Writers never starve but a steady stream of them starves the readers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct WriterPreferring;
+
+/// Readers go first: they only queue up while a writer is actually writing
+/// and they all come in as soon as it's done.
+///
+/// Readers never starve but a steady stream of them starves the writers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ReaderPreferring;
+
+/// Readers and writers take turns: readers queue up as soon as a writer
+/// shows up, but when a writer is done, all of them come in before the next one.
+///
+/// Nobody starves: readers wait for one writer at most while writers wait
+/// for one batch of readers per writer ahead of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PhaseFair;
+
+impl sealed::Sealed for WriterPreferring {}
+impl sealed::Sealed for ReaderPreferring {}
+impl sealed::Sealed for PhaseFair {}
+
+impl Policy for WriterPreferring {
+    const READERS_FIRST: bool = false;
+    const READER_BATCHES: bool = false;
+}
+
+impl Policy for ReaderPreferring {
+    const READERS_FIRST: bool = true;
+    const READER_BATCHES: bool = true;
+}
+
+impl Policy for PhaseFair {
+    const READERS_FIRST: bool = false;
+    const READER_BATCHES: bool = true;
+}
+
 /// An efficient reader-writer lock (rwlock).
 ///
 /// To recap, the invariant is: Either multiple readers or a single writer.
@@ -20,143 +80,659 @@ unsafe fn likely(b: bool) -> bool { b }
 ///
 /// Thus, it is not reentrant and it may misbehave if used incorrectly
 /// (i.e. you can release even if you're not even holding it).
-/// It's also not fair and it is designed to always prefer writers over readers.
+/// Who gets to go next is up to the `Policy` `P`, which prefers writers by default.
+///
+/// On top of that, one thread at a time can hold an upgradable read lock
+/// (see `UpgradableRwLock`). It counts as a reader but keeps writers out,
+/// so it can be upgraded to a write lock without a writer slipping in.
+///
+/// The lock is heavily optimized for uncontended scenarios. When writers are
+/// competing with each other, one of them spins for a while and a release
+/// passes the lock on to it. Otherwise, a release hands the lock over to the
+/// writer it wakes up, so nobody wakes up for nothing. To keep the spinning
+/// writers from starving the sleeping ones, that happens every 0.5 ms anyways.
+/// Passing the lock on to the spinning writer just lets any writer take it
+/// though, so one that shows up at the same time may get it instead (and the
+/// spinning one goes to sleep). That's as fair as a lock without hand-offs
+/// and it saves telling the spinning writer apart from everyone else.
+///
+/// The state is a single 64-bit word, so it supports up to 2^21 - 1
+/// simultaneous readers (and as many queued ones) and 2047 writers
+/// (including waiting ones).
+/// Whoever finds the count they're adding to full backs off right away:
+/// the blocking operations yield until there's room again while the
+/// non-blocking (and timed out) ones fail, so the lock keeps working.
 ///
-/// The lock is heavily optimized for uncontended scenarios but performance
-/// should be close to ideal in any case except for when multiple writers
-/// are competing with each other.
+/// That word has to be updated atomically, so this (along with every
+/// reader-writer lock built on it) only exists on targets with 64-bit atomics.
+/// On the others, such as 32-bit mips and powerpc, there are just the mutexes.
 ///
-/// The only drawback of the implementation is that it only supports a
-/// maximum of 511 simultaneous readers, queued readers and writers each.
-/// Anything beyond that overflows, causing all operations on the lock
-/// (starting from and including the one that overflowed it) to panic.
-/// This means that the rwlock invariant can never be compromised this way.
-pub struct RwFutex2 {
-    futex: AtomicU32,
+/// Without futexes, waiting readers and writers keep yielding the CPU instead (like with `Futex`).
+///
+/// Like `Futex`, a `RwFutex2<Shared>` can be placed in shared memory (see `init_at`).
+#[repr(C)]
+pub struct RwFutex2<S: Scope = Private, P: Policy = WriterPreferring> {
+    futex: AtomicU64,
+    // CLOCK_MONOTONIC nanoseconds of the next forced hand-off (see step_down)
+    next_handoff: AtomicU64,
+    scope: PhantomData<S>,
+    policy: PhantomData<P>,
 }
 
-const M_DEATH: u32          = 0b10100000000010000000001000000000;
-const F_WRITE_SHOVE: u32    = 0b01000000000000000000000000000000;
-const M_WRITERS: u32        = 0b00011111111100000000000000000000;
-const M_READERS_QUEUED: u32 = 0b00000000000001111111110000000000;
-const M_READERS: u32        = 0b00000000000000000000000111111111;
+// The futex syscalls only work on 32-bit words, so everyone waits on the half
+// of the state that changes when what they're waiting for happens:
+// the owner waits for the readers in the low half to leave
+// while everyone else waits for the writers and flags in the high half.
+// (The queued readers straddle both but nobody waits for them to change.)
+const F_UPGRADABLE: u64        = 0x8000_0000_0000_0000;
+// any waiting writer may take the lock (if nobody was asleep when it was handed over)
+const F_WRITE_SHOVE: u64       = 0x4000_0000_0000_0000;
+// flips whenever a writer lets the queued readers in (see wait_queued)
+const F_PHASE: u64             = 0x2000_0000_0000_0000;
+// the owner lets readers in while it waits for the others to leave (ReaderPreferring)
+const F_READERS_WELCOME: u64   = 0x1000_0000_0000_0000;
+// there may be threads waiting for the upgradable read lock
+const F_UPGRADABLE_PARKED: u64 = 0x0800_0000_0000_0000;
+// a writer panicked while holding the lock (see PoisonableLock)
+const F_POISONED: u64          = 0x0400_0000_0000_0000;
+// the lock was handed over to the writer that got woken up (see hand_over)
+const F_HANDOFF: u64           = 0x0200_0000_0000_0000;
+// a waiting writer is spinning, so the next release passes the lock on to it (see spin_write)
+const F_WRITER_SPINNING: u64   = 0x0100_0000_0000_0000;
+// the top bit of each count, which means it's full
+// (this is part of the counts so they stay exact while an overflow is rolled back)
+const M_OVERFLOW: u64          = 0x0080_0800_0020_0000;
+const M_WRITERS: u64           = 0x00ff_f000_0000_0000;
+const M_READERS_QUEUED: u64    = 0x0000_0fff_ffc0_0000;
+const M_READERS: u64           = 0x0000_0000_003f_ffff;
+
+const ONE_WRITER: u64          = 0x0000_1000_0000_0000;
+const ONE_READER_QUEUED: u64   = 0x0000_0000_0040_0000;
+const ONE_READER: u64          = 0x0000_0000_0000_0001;
 
-const ONE_WRITER: u32       = 0b00000000000100000000000000000000;
-const ONE_READER_QUEUED: u32 =0b00000000000000000000010000000000;
-const ONE_READER: u32       = 0b00000000000000000000000000000001;
+#[cfg(target_endian = "little")]
+const READERS_HALF: usize = 0;
+#[cfg(target_endian = "big")]
+const READERS_HALF: usize = 1;
 
 const ID_READER: i32 = 1;
 const ID_WRITER: i32 = 2;
+// the writer that owns the lock but is still waiting for readers to leave
+// (the only one waiting on the readers half)
+const ID_WRITER_OWNER: i32 = 4;
+// threads waiting for the upgradable read lock
+const ID_UPGRADABLE: i32 = 8;
 
-#[inline(always)]
-fn safe_add(dst: &AtomicU32, val: u32, ordering: Ordering) -> u32 {
-    let mut ret = dst.fetch_add(val, ordering);
-    if ret & M_DEATH != 0 { die(dst) }
-    ret = ret.wrapping_add(val);
-    if ret & M_DEATH != 0 { die(dst) }
-    ret
-}
+// how long a waiting writer spins before going to sleep (like Futex's default)
+const WRITER_SPINS: u32 = 100;
 
-#[inline(always)]
-fn safe_sub(dst: &AtomicU32, val: u32, ordering: Ordering) -> u32 {
-    safe_add(dst, val.wrapping_neg(), ordering)
-}
+// the most readers that fit
+#[cfg(test)]
+pub(crate) const MAX_READERS: u64 = M_READERS & !M_OVERFLOW;
+
+impl<S: Scope> RwFutex2<S> {
+    /// Creates a new, writer-preferring instance.
+    pub fn new() -> RwFutex2<S> {
+        RwFutex2::default()
+    }
 
-#[cold]
-#[inline(never)]
-fn die(dst: &AtomicU32) -> ! {
-    // make it as unlikely as possible for any possible group
-    // of concurrent operations to accidentally revive this
-    dst.store(M_DEATH, Ordering::SeqCst);
-    panic!("Spontaneous futex combustion! (overflow)");
+    /// Creates an instance that already has `readers` readers.
+    #[cfg(test)]
+    pub(crate) fn with_readers(readers: u64) -> RwFutex2<S> {
+        RwFutex2 {
+            futex: AtomicU64::new(readers * ONE_READER),
+            next_handoff: AtomicU64::new(0),
+            scope: PhantomData,
+            policy: PhantomData,
+        }
+    }
 }
 
-impl RwFutex2 {
+impl<S: Scope, P: Policy> RwFutex2<S, P> {
+    /// Creates a new instance with the given policy.
+    pub fn with_policy(_: P) -> RwFutex2<S, P> {
+        RwFutex2::default()
+    }
+
+    /// Adds `val` to the state and returns the new value.
+    ///
+    /// If that fills up a count, we roll it back (see `add_slow`).
+    /// Returns `None` if `deadline` passed before there was room.
+    #[inline(always)]
+    fn add(&self, val: u64, ordering: Ordering, deadline: Option<Instant>) -> Option<u64> {
+        let ret = self.futex.fetch_add(val, ordering).wrapping_add(val);
+        if unsafe { likely(ret & M_OVERFLOW == 0) } {
+            Some(ret)
+        } else {
+            self.add_slow(val, ordering, deadline)
+        }
+    }
+
+    /// Rolls back an addition that filled up a count and tries again once there's room.
+    ///
+    /// Nobody is going to wake us up when that happens so we just yield in the meantime.
+    #[cold]
     #[inline(never)]
-    fn acquire_read_slow(&self, mut val: u32) {
+    fn add_slow(&self, val: u64, ordering: Ordering, deadline: Option<Instant>) -> Option<u64> {
         loop {
-            if val & M_WRITERS == 0 {
+            // (the counts are still exact in the meantime so nobody else gets confused)
+            self.futex.fetch_sub(val, Ordering::Relaxed);
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return None;
+            }
+            thread::yield_now();
+
+            let ret = self.futex.fetch_add(val, ordering).wrapping_add(val);
+            if ret & M_OVERFLOW == 0 {
+                return Some(ret);
+            }
+        }
+    }
+
+    /// Subtracts `val` from the state and returns the new value.
+    #[inline(always)]
+    fn sub(&self, val: u64, ordering: Ordering) -> u64 {
+        self.futex.fetch_sub(val, ordering).wrapping_sub(val)
+    }
+
+    /// The half of the state that `id` waits on.
+    #[inline(always)]
+    fn half(&self, id: i32) -> &AtomicU32 {
+        let i = if id == ID_WRITER_OWNER { READERS_HALF } else { 1 - READERS_HALF };
+        // (only ever handed to the kernel, we never access it as an AtomicU32 ourselves)
+        unsafe { &*(&self.futex as *const AtomicU64 as *const AtomicU32).add(i) }
+    }
+
+    /// Waits for the relevant half of the futex to change from `val`.
+    ///
+    /// Returns `None` if we gave up because `deadline` passed
+    /// and otherwise whether a wake picked us (as opposed to the value
+    /// changing before we got to sleep or a signal).
+    #[inline(always)]
+    fn wait(&self, val: u64, id: i32, deadline: Option<Instant>) -> Option<bool> {
+        let val = if id == ID_WRITER_OWNER { val as u32 } else { (val >> 32) as u32 };
+        let res = match deadline {
+            None => futex_wait_bitset::<S>(self.half(id), val, id),
+            Some(deadline) => futex_wait_bitset_until::<S>(self.half(id), val, id, deadline),
+        };
+        let picked = res.is_ok();
+        if waited(res, deadline) {
+            Some(picked)
+        } else {
+            None
+        }
+    }
+
+    /// Wakes up to `count` threads waiting for `id` and returns how many there were.
+    #[inline(always)]
+    fn wake(&self, count: u32, id: i32) -> i32 {
+        woken(futex_wake_bitset::<S>(self.half(id), count, id))
+    }
+
+    /// Whether a reader may come in.
+    #[inline(always)]
+    fn admits_readers(val: u64) -> bool {
+        // (nobody can be writing while there's an upgradable reader)
+        (val & M_WRITERS == 0) || (P::READERS_FIRST && (val & (F_READERS_WELCOME | F_UPGRADABLE) != 0))
+    }
+
+    /// Releases a write lock when it's not just us in the state.
+    ///
+    /// If nobody is waiting after all (the phase has flipped or the lock is
+    /// poisoned), we also reset the phase so the next release gets by with
+    /// the fast path. Nobody can be looking at it: the readers of the last
+    /// phase were all counted as readers so they've left by now.
+    #[inline(never)]
+    fn release_write_slow(&self, mut val: u64) {
+        while val & (M_WRITERS | M_READERS_QUEUED | F_UPGRADABLE_PARKED) == ONE_WRITER {
+            match self.futex.compare_exchange_weak(val, (val - ONE_WRITER) & !F_PHASE,
+                                                   Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => return,
+                Err(x) => val = x,
+            }
+        }
+        self.step_down(val, |val| val.wrapping_sub(ONE_WRITER), true);
+    }
+
+    /// Lets all queued readers in.
+    ///
+    /// They become readers right away (so the next writer waits for them)
+    /// and they find out because the phase flipped.
+    /// Returns `None` if they don't fit.
+    #[inline]
+    fn grant(val: u64) -> Option<u64> {
+        let queued = (val & M_READERS_QUEUED) / ONE_READER_QUEUED;
+        let newval = (val & !M_READERS_QUEUED) + queued * ONE_READER;
+        if newval & M_OVERFLOW != 0 {
+            None
+        } else {
+            Some(newval ^ F_PHASE)
+        }
+    }
+
+    /// Wakes up the readers and upgradable readers that might be able to come in now.
+    ///
+    /// `granted` is whether we just let the queued readers in.
+    #[inline]
+    fn wake_readers(&self, val: u64, granted: bool) {
+        if granted || ((val & M_READERS_QUEUED != 0) && Self::admits_readers(val)) {
+            self.wake(i32::MAX as u32, ID_READER);
+        }
+        if val & (M_WRITERS | F_UPGRADABLE | F_UPGRADABLE_PARKED) == F_UPGRADABLE_PARKED {
+            self.unpark_upgradable();
+        }
+    }
+
+    /// Lets the parked upgradable readers try again.
+    #[cold]
+    fn unpark_upgradable(&self) {
+        if self.futex.fetch_and(!F_UPGRADABLE_PARKED, Ordering::Relaxed) & F_UPGRADABLE_PARKED != 0 {
+            // (if someone beat them to it in the meantime, they park again)
+            self.wake(i32::MAX as u32, ID_UPGRADABLE);
+        }
+    }
+
+    #[inline(never)]
+    fn acquire_read_slow(&self, mut val: u64, deadline: Option<Instant>) -> bool {
+        loop {
+            if Self::admits_readers(val) {
                 // got it
-                break;
+                return true;
             }
 
             // writer lock - move from readers to readers_queued
-            val = safe_add(&self.futex, ONE_READER_QUEUED - ONE_READER, Ordering::Acquire);
+            let newval = val.wrapping_sub(ONE_READER).wrapping_add(ONE_READER_QUEUED);
+            if newval & M_OVERFLOW != 0 {
+                // the queue is full - back off and try again (like add_slow)
+                match self.futex.compare_exchange(val, val.wrapping_sub(ONE_READER), Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        if val.wrapping_sub(ONE_READER) & M_READERS == 0 {
+                            // (see below)
+                            self.wake(1, ID_WRITER_OWNER);
+                        }
+                        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                            return false;
+                        }
+                        thread::yield_now();
+                        val = match self.add(ONE_READER, Ordering::Acquire, deadline) {
+                            Some(val) => val,
+                            None => return false,
+                        };
+                    }
+                    Err(x) => val = x,
+                }
+                continue;
+            }
+            if let Err(x) = self.futex.compare_exchange(val, newval, Ordering::Relaxed, Ordering::Relaxed) {
+                val = x;
+                continue;
+            }
 
-            if val & M_WRITERS == 0 {
-                // writer unlocked in the meantime - leave queue and retry
-            } else {
-                if (val & M_READERS == 0) && (val & M_WRITERS != 0) {
-                    // fix deadlock if our temporary new reader
-                    // interleaved with release_read() calls
-                    // so that we reach zero HERE => might have to wake up writers
-                    futex_wake_bitset(&self.futex, 1, ID_WRITER);
+            if newval & M_READERS == 0 {
+                // fix deadlock if our temporary new reader
+                // interleaved with release_read() calls
+                // so that we reach zero HERE => might have to wake up writers
+                self.wake(1, ID_WRITER_OWNER);
+            }
+            return self.wait_queued(newval, deadline);
+        }
+    }
+
+    /// Waits in the queue until we're let in.
+    ///
+    /// Usually, a writer lets all of the queued readers in at once (see `grant`)
+    /// but if the writers leave without doing so, we let ourselves in.
+    /// Only a writer that actually got to write may do that: we're counted as
+    /// readers then, so no other writer gets to write (and flip the phase back)
+    /// before we've noticed.
+    ///
+    /// Returns `false` if we gave up because `deadline` passed.
+    fn wait_queued(&self, mut val: u64, deadline: Option<Instant>) -> bool {
+        let phase = val & F_PHASE;
+        loop {
+            if val & F_PHASE != phase {
+                // we've been let in
+                return true;
+            }
+
+            if Self::admits_readers(val) {
+                let newval = val.wrapping_sub(ONE_READER_QUEUED).wrapping_add(ONE_READER);
+                if newval & M_OVERFLOW != 0 {
+                    // too many readers - wait for one to leave
+                    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                        return self.leave_queue(phase);
+                    }
+                    thread::yield_now();
+                    val = self.futex.load(Ordering::Acquire);
+                    continue;
+                }
+                match self.futex.compare_exchange(val, newval, Ordering::Acquire, Ordering::Acquire) {
+                    Ok(_) => return true,
+                    Err(x) => val = x,
                 }
+                continue;
+            }
 
-                futex_wait_bitset(&self.futex, val, ID_READER);
+            if self.wait(val, ID_READER, deadline).is_none() {
+                return self.leave_queue(phase);
             }
+            val = self.futex.load(Ordering::Acquire);
+        }
+    }
 
-            // no longer waiting - leave the queue
-            val = safe_add(&self.futex, ONE_READER.wrapping_sub(ONE_READER_QUEUED), Ordering::Acquire);
+    /// Backs out of a timed out read acquisition.
+    ///
+    /// Returns whether we ended up with the lock after all.
+    #[cold]
+    fn leave_queue(&self, phase: u64) -> bool {
+        let mut val = self.futex.load(Ordering::Acquire);
+        loop {
+            if val & F_PHASE != phase {
+                // we've just been let in - better late than never
+                return true;
+            }
+            match self.futex.compare_exchange(val, val.wrapping_sub(ONE_READER_QUEUED), Ordering::Relaxed, Ordering::Acquire) {
+                Ok(_) => return false,
+                Err(x) => val = x,
+            }
         }
     }
 
     #[inline(never)]
-    fn acquire_write_slow(&self, mut val: u32) {
-        let mut have_lock = false;
+    fn acquire_write_slow(&self, mut val: u64, mut have_lock: bool, deadline: Option<Instant>) -> bool {
+        let mut spun = false;
         loop {
+            let id;
             if have_lock {
                 // I'm just waiting for readers to finish
                 if val & M_READERS == 0 {
-                    // got it
-                    break;
+                    if val & F_READERS_WELCOME == 0 {
+                        // got it
+                        return true;
+                    }
+                    // shut the door (unless a reader slipped in just now)
+                    match self.futex.compare_exchange(val, val & !F_READERS_WELCOME, Ordering::Acquire, Ordering::Relaxed) {
+                        Ok(_) => return true,
+                        Err(x) => val = x,
+                    }
+                    continue;
+                }
+                if P::READERS_FIRST && (val & F_READERS_WELCOME == 0) {
+                    // readers are welcome to come in while we wait
+                    match self.futex.compare_exchange(val, val | F_READERS_WELCOME, Ordering::Relaxed, Ordering::Relaxed) {
+                        Ok(_) => {
+                            val |= F_READERS_WELCOME;
+                            self.wake_readers(val, false);
+                        }
+                        Err(x) => val = x,
+                    }
+                    continue;
                 }
+                id = ID_WRITER_OWNER;
             } else if val & F_WRITE_SHOVE != 0 {
                 // I'm one of (potentially many) waiting writers
                 // (slow path)
 
                 // hunger games: whoever manages to eat the shove flag wins
-                let newval = self.futex.compare_and_swap(val, val & !F_WRITE_SHOVE, Ordering::Acquire);
+                let newval = match self.futex.compare_exchange(val, val & !F_WRITE_SHOVE, Ordering::Acquire, Ordering::Relaxed) {
+                    Ok(x) | Err(x) => x,
+                };
                 if val == newval {
                     // we won the race -> lock is ours
-                    break;
+                    // (but there may still be readers, e.g. the ones the
+                    // previous owner let in or because it timed out waiting for them)
+                    have_lock = true;
+                    val &= !F_WRITE_SHOVE;
                 } else {
                     val = newval;
-                    continue;
                 }
-            } else if val & M_WRITERS == ONE_WRITER {
+                continue;
+            } else if (val & M_WRITERS == ONE_WRITER) && (val & (F_UPGRADABLE | F_HANDOFF) == 0) {
                 // I'm the only writer
                 have_lock = true;
-                if val & M_READERS == 0 {
-                    // got it!
-                    break;
-                }
-            } // else a writer is active right now
+                continue;
+            } else if !spun && (val & (F_WRITER_SPINNING | F_HANDOFF) == 0) {
+                // a writer (or an upgradable reader) is active right now
+                // but it might be done soon
+                spun = true;
+                val = self.spin_write(val);
+                continue;
+            } else {
+                // (same but someone else is spinning or the lock was just handed over to someone else)
+                id = ID_WRITER;
+            }
 
             // (slowest path - we wait)
-            futex_wait_bitset(&self.futex, val, ID_WRITER);
+            let picked = match self.wait(val, id, deadline) {
+                Some(picked) => picked,
+                None => return self.abandon_write(have_lock),
+            };
 
             val = self.futex.load(Ordering::Acquire);
+            if picked && id == ID_WRITER {
+                // whoever released the lock woke us up to hand it over
+                // (unless a writer that was woken up for the shove flag or
+                //  one that gave up took it first)
+                while val & F_HANDOFF != 0 {
+                    match self.futex.compare_exchange(val, val & !F_HANDOFF, Ordering::Acquire, Ordering::Acquire) {
+                        Ok(_) => {
+                            have_lock = true;
+                            val &= !F_HANDOFF;
+                        }
+                        Err(x) => val = x,
+                    }
+                }
+            }
         }
     }
 
+    /// Spins for a while in the hope that the write lock is passed on to us soon.
+    ///
+    /// Only one writer spins at a time and the next release passes the lock
+    /// on to it with the shove flag (see `step_down`), so we're done as soon
+    /// as the spinning flag is gone. Returns the current value.
     #[inline(never)]
-    fn release_write_slow(&self, val: u32) {
-        if val & M_WRITERS != 0 {
-            // there are other writers waiting
-            // we set the shove flag to signal that one of them may wake up now
-            self.futex.fetch_or(F_WRITE_SHOVE, Ordering::Release);
-            futex_wake_bitset(&self.futex, 1, ID_WRITER);
+    fn spin_write(&self, val: u64) -> u64 {
+        if let Err(x) = self.futex.compare_exchange(val, val | F_WRITER_SPINNING, Ordering::Relaxed, Ordering::Relaxed) {
+            return x;
+        }
+
+        for _ in 0..WRITER_SPINS {
+            hint::spin_loop();
+            let val = self.futex.load(Ordering::Relaxed);
+            if val & F_WRITER_SPINNING == 0 {
+                return val;
+            }
+        }
+
+        // nothing yet - stop spinning (unless it just happened)
+        let val = self.futex.fetch_and(!F_WRITER_SPINNING, Ordering::Relaxed);
+        val & !F_WRITER_SPINNING
+    }
+
+    /// Backs out of a timed out write acquisition.
+    ///
+    /// Returns whether we ended up with the lock after all.
+    #[cold]
+    fn abandon_write(&self, have_lock: bool) -> bool {
+        if have_lock {
+            // we own the lock (but readers are still active)
+            // so we pass it on just like a regular release would
+            if P::READERS_FIRST {
+                self.futex.fetch_and(!F_READERS_WELCOME, Ordering::Relaxed);
+            }
+            self.pass_on();
+            return false;
+        }
+
+        let mut val = self.futex.load(Ordering::Relaxed);
+        loop {
+            if val & (F_WRITE_SHOVE | F_HANDOFF) != 0 {
+                // the lock was just handed over, possibly to us - we can't just leave
+                // (a hand-off may be for a writer that's yet to be woken up, but if we left,
+                //  newcomers would see a free lock while it's being handed over)
+                match self.futex.compare_exchange(val, val & !(F_WRITE_SHOVE | F_HANDOFF), Ordering::Acquire, Ordering::Relaxed) {
+                    Ok(_) if val & M_READERS == 0 => return true, // better late than never
+                    Ok(_) => {
+                        self.pass_on();
+                        return false;
+                    }
+                    Err(newval) => val = newval,
+                }
+            } else {
+                let newval = val.wrapping_sub(ONE_WRITER);
+                match self.futex.compare_exchange(val, newval, Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        if newval & M_WRITERS == 0 {
+                            // we were waiting for an upgradable reader and held up
+                            // everyone behind us, so let them in now
+                            self.wake_readers(newval, false);
+                        }
+                        return false;
+                    }
+                    Err(newval) => val = newval,
+                }
+            }
+        }
+    }
+
+    /// Gives up the write lock without having used it.
+    ///
+    /// Unlike a regular release, this can't let the queued readers in (see `wait_queued`).
+    fn pass_on(&self) {
+        self.step_down(self.futex.load(Ordering::Relaxed), |val| val.wrapping_sub(ONE_WRITER), false);
+    }
+
+    /// Hands the lock over to a waiting writer after `F_HANDOFF` was set.
+    ///
+    /// We wake up a single writer and the lock is theirs (see `acquire_write_slow`),
+    /// so writers that show up in the meantime can't snatch it away and nobody
+    /// else wakes up only to go back to sleep. The kernel wakes up waiters in
+    /// the order they went to sleep, so it's first come, first served.
+    ///
+    /// If none of them are asleep yet, any of them may take it (with the shove flag).
+    /// Writers that give up take the hand-off with them (see `abandon_write`),
+    /// so there's always someone left to take it.
+    fn hand_over(&self) {
+        if self.wake(1, ID_WRITER) != 0 {
+            return;
+        }
+
+        let mut val = self.futex.load(Ordering::Relaxed);
+        while val & F_HANDOFF != 0 {
+            let newval = (val & !(F_HANDOFF | F_WRITER_SPINNING)) | F_WRITE_SHOVE;
+            match self.futex.compare_exchange(val, newval, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => {
+                    // someone may have gone to sleep since
+                    self.wake(1, ID_WRITER);
+                    return;
+                }
+                Err(x) => val = x,
+            }
+        }
+    }
+
+    /// Whether the lock should be handed over to a sleeping writer
+    /// even though another one is spinning.
+    fn handoff_due(&self) -> bool {
+        // only whoever holds the lock gets here
+        let now = monotonic_nanos();
+        if now >= self.next_handoff.load(Ordering::Relaxed) {
+            self.next_handoff.store(now + FAIR_INTERVAL_NS, Ordering::Relaxed);
+            true
         } else {
-            // no writers -> wake up readers (if any)
-            if val & M_READERS_QUEUED != 0 {
-                futex_wake_bitset(&self.futex, i32::MAX as u32, ID_READER);
+            false
+        }
+    }
+
+    /// Takes the upgradable read lock if neither writers nor another upgradable reader have it.
+    ///
+    /// Returns the current value otherwise.
+    #[inline]
+    fn try_take_upgradable(&self, mut val: u64) -> Result<(), u64> {
+        while val & (M_WRITERS | F_UPGRADABLE) == 0 {
+            let newval = val.wrapping_add(ONE_READER) | F_UPGRADABLE;
+            if newval & M_OVERFLOW != 0 {
+                // too many readers - wait for one to leave
+                thread::yield_now();
+                val = self.futex.load(Ordering::Relaxed);
+                continue;
+            }
+            match self.futex.compare_exchange_weak(val, newval, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => return Ok(()),
+                Err(x) => val = x,
+            }
+        }
+        Err(val)
+    }
+
+    #[inline(never)]
+    fn acquire_upgradable_slow(&self, mut val: u64) {
+        loop {
+            // park so releases know they have to wake us up
+            let parked = val | F_UPGRADABLE_PARKED;
+            if val == parked || self.futex.compare_exchange(val, parked, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+                self.wait(parked, ID_UPGRADABLE, None);
+            }
+
+            match self.try_take_upgradable(self.futex.load(Ordering::Relaxed)) {
+                Ok(()) => return,
+                Err(x) => val = x,
+            }
+        }
+    }
+
+    /// Gives up our claim to exclusivity by applying `step` to the value.
+    ///
+    /// This is for releasing (or downgrading) a write lock and for the upgradable flag:
+    /// if there are writers waiting, we pass the lock on to the spinning one or
+    /// hand it over to a sleeping one.
+    /// Whether the queued readers go before them is up to the policy.
+    /// They're only let in if we may `grant` it, which only a writer that got
+    /// to write may do (see `wait_queued`).
+    #[inline(never)]
+    fn step_down<F: Fn(u64) -> u64>(&self, mut val: u64, step: F, grant: bool) {
+        let mut handoff_due = None;
+        let (newval, granted) = loop {
+            let mut newval = step(val);
+            if newval & M_OVERFLOW != 0 {
+                // (only when downgrading) too many readers - wait for one to leave
+                thread::yield_now();
+                val = self.futex.load(Ordering::Relaxed);
+                continue;
+            }
+
+            // (this is a single step so nobody can take the lock before it's handed over)
+            let hand_over = newval & M_WRITERS != 0;
+            let mut granted = false;
+            if grant && (newval & M_READERS_QUEUED != 0) && (P::READER_BATCHES || !hand_over) {
+                if let Some(x) = Self::grant(newval) {
+                    newval = x;
+                    granted = true;
+                }
+            }
+            if hand_over {
+                if (newval & F_WRITER_SPINNING != 0) && !*handoff_due.get_or_insert_with(|| self.handoff_due()) {
+                    // the spinning writer takes it (no need to wake anyone up)
+                    newval = (newval & !F_WRITER_SPINNING) | F_WRITE_SHOVE;
+                } else {
+                    newval |= F_HANDOFF;
+                }
+            }
+            match self.futex.compare_exchange(val, newval, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => break (newval, granted),
+                Err(x) => val = x,
             }
+        };
+
+        if newval & F_HANDOFF != 0 {
+            self.hand_over();
         }
+        self.wake_readers(newval, granted);
     }
 }
 
-impl RwLock for RwFutex2 {
+impl<S: Scope, P: Policy> RwLock for RwFutex2<S, P> {
     type ReadLockState = ();
     type WriteLockState = ();
 
@@ -165,12 +741,12 @@ impl RwLock for RwFutex2 {
     /// This blocks until the lock is ours.
     #[inline]
     fn acquire_read(&self) {
-        let val = safe_add(&self.futex, ONE_READER, Ordering::Acquire);
-        if unsafe { likely(val & M_WRITERS == 0) } {
+        let val = self.add(ONE_READER, Ordering::Acquire, None).unwrap();
+        if unsafe { likely(Self::admits_readers(val)) } {
             // got it
             return;
         }
-        self.acquire_read_slow(val)
+        self.acquire_read_slow(val, None);
     }
 
     /// Acquries a write lock.
@@ -178,50 +754,263 @@ impl RwLock for RwFutex2 {
     /// This blocks until the lock is ours.
     #[inline]
     fn acquire_write(&self) {
-        let val = safe_add(&self.futex, ONE_WRITER, Ordering::Acquire);
-        if unsafe { likely((val & F_WRITE_SHOVE == 0)
+        let val = self.add(ONE_WRITER, Ordering::Acquire, None).unwrap();
+        if unsafe { likely((val & (F_WRITE_SHOVE | F_HANDOFF) == 0)
                            && (val & M_WRITERS == ONE_WRITER)
                            && (val & M_READERS == 0)) } {
             // got it
             return;
         }
-        self.acquire_write_slow(val)
+        self.acquire_write_slow(val, false, None);
     }
 
     /// Releases a read lock.
     #[inline]
     fn release_read(&self, _: ()) {
-        let val = safe_sub(&self.futex, ONE_READER, Ordering::Release);
+        let val = self.sub(ONE_READER, Ordering::Release);
         if (val & M_READERS == 0) && (val & M_WRITERS != 0) {
-            // was 1 => now 0 => no more readers => writers queued => wake one up
-            futex_wake_bitset(&self.futex, 1, ID_WRITER);
+            // was 1 => now 0 => no more readers => writers queued => wake the owner up
+            self.wake(1, ID_WRITER_OWNER);
         }
     }
 
     /// Releases a write lock.
     #[inline]
     fn release_write(&self, _: ()) {
-        let val = safe_sub(&self.futex, ONE_WRITER, Ordering::Release);
-        if unsafe { likely((val & M_WRITERS == 0)
-                           && (val & M_READERS_QUEUED == 0)) } {
-            return;
+        if let Err(val) = self.futex.compare_exchange(ONE_WRITER, 0, Ordering::Release, Ordering::Relaxed) {
+            self.release_write_slow(val);
+        }
+    }
+}
+
+impl<S: Scope, P: Policy> TimedRwLock for RwFutex2<S, P> {
+    /// Attempts to acquire a read lock, blocking until `deadline` at most.
+    fn try_acquire_read_until(&self, deadline: Instant) -> Option<()> {
+        let val = self.add(ONE_READER, Ordering::Acquire, Some(deadline))?;
+        if Self::admits_readers(val) || self.acquire_read_slow(val, Some(deadline)) {
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    /// Attempts to acquire a write lock, blocking until `deadline` at most.
+    fn try_acquire_write_until(&self, deadline: Instant) -> Option<()> {
+        let val = self.add(ONE_WRITER, Ordering::Acquire, Some(deadline))?;
+        if ((val & (F_WRITE_SHOVE | F_HANDOFF) == 0)
+            && (val & M_WRITERS == ONE_WRITER)
+            && (val & M_READERS == 0))
+            || self.acquire_write_slow(val, false, Some(deadline)) {
+            Some(())
+        } else {
+            None
+        }
+    }
+}
+
+impl<S: Scope, P: Policy> TryRwLock for RwFutex2<S, P> {
+    /// Attempts to acquire a read lock without blocking.
+    ///
+    /// Unlike the blocking variant, this never queues up.
+    /// It also fails if there are too many readers already.
+    fn try_acquire_read(&self) -> Option<()> {
+        let mut val = self.futex.load(Ordering::Relaxed);
+        loop {
+            let newval = val.wrapping_add(ONE_READER);
+            if !Self::admits_readers(val) || (newval & M_OVERFLOW != 0) {
+                return None;
+            }
+
+            match self.futex.compare_exchange_weak(val, newval, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => return Some(()),
+                Err(x) => val = x,
+            }
+        }
+    }
+
+    /// Attempts to acquire a write lock without blocking.
+    ///
+    /// Unlike the blocking variant, this never queues up.
+    fn try_acquire_write(&self) -> Option<()> {
+        let mut val = self.futex.load(Ordering::Relaxed);
+        loop {
+            if val & (M_WRITERS | M_READERS | F_WRITE_SHOVE | F_HANDOFF) != 0 {
+                return None;
+            }
+
+            // (queued readers are the only thing that can be left here
+            //  and they are about to find out that they have to wait again)
+            match self.futex.compare_exchange_weak(val, val + ONE_WRITER, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => return Some(()),
+                Err(x) => val = x,
+            }
+        }
+    }
+}
+
+impl<S: Scope, P: Policy> DowngradableRwLock for RwFutex2<S, P> {
+    /// Turns a write lock into a read lock.
+    ///
+    /// This is just like a release: depending on the policy, the queued readers
+    /// come in right away or the next writer gets the lock as soon as the
+    /// readers (including us) are gone.
+    #[inline]
+    fn downgrade(&self, _: ()) {
+        self.step_down(self.futex.load(Ordering::Relaxed), |val| val.wrapping_sub(ONE_WRITER).wrapping_add(ONE_READER), true);
+    }
+}
+
+impl<S: Scope, P: Policy> UpgradableRwLock for RwFutex2<S, P> {
+    type UpgradableLockState = ();
+
+    /// Acquires an upgradable read lock.
+    ///
+    /// This blocks until the lock is ours.
+    #[inline]
+    fn acquire_upgradable(&self) {
+        if let Err(val) = self.try_take_upgradable(self.futex.load(Ordering::Relaxed)) {
+            self.acquire_upgradable_slow(val);
+        }
+    }
+
+    /// Releases an upgradable read lock.
+    #[inline]
+    fn release_upgradable(&self, _: ()) {
+        self.step_down(self.futex.load(Ordering::Relaxed), |val| (val & !F_UPGRADABLE).wrapping_sub(ONE_READER), false);
+    }
+
+    /// Turns an upgradable read lock into a write lock.
+    ///
+    /// This blocks until all other readers are gone.
+    fn upgrade(&self, _: ()) {
+        // no writer can own the lock while we're upgradable,
+        // so we become the owner right away
+        let val = self.add(ONE_WRITER.wrapping_sub(ONE_READER).wrapping_sub(F_UPGRADABLE), Ordering::Acquire, None).unwrap();
+        self.acquire_write_slow(val, true, None);
+    }
+
+    /// Attempts to turn an upgradable read lock into a write lock without blocking.
+    ///
+    /// This only succeeds if we're the only reader left
+    /// (and there aren't too many writers already).
+    fn try_upgrade(&self, _: ()) -> Result<(), ()> {
+        let mut val = self.futex.load(Ordering::Relaxed);
+        loop {
+            let newval = val.wrapping_add(ONE_WRITER).wrapping_sub(ONE_READER).wrapping_sub(F_UPGRADABLE);
+            if (val & M_READERS != ONE_READER) || (newval & M_OVERFLOW != 0) {
+                return Err(());
+            }
+
+            match self.futex.compare_exchange_weak(val, newval, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => return Ok(()),
+                Err(x) => val = x,
+            }
         }
-        self.release_write_slow(val)
+    }
+
+    /// Turns an upgradable read lock into a regular read lock.
+    #[inline]
+    fn downgrade_upgradable(&self, _: ()) {
+        self.step_down(self.futex.load(Ordering::Relaxed), |val| val & !F_UPGRADABLE, false);
+    }
+}
+
+impl<S: Scope, P: Policy> PoisonableLock for RwFutex2<S, P> {
+    /// Marks the lock as poisoned.
+    ///
+    /// Only whoever holds the write lock may do this.
+    fn poison(&self) {
+        self.futex.fetch_or(F_POISONED, Ordering::Relaxed);
+    }
+
+    /// Whether the lock is poisoned.
+    fn is_poisoned(&self) -> bool {
+        self.futex.load(Ordering::Relaxed) & F_POISONED != 0
+    }
+
+    /// Clears the poisoned state.
+    fn clear_poison(&self) {
+        self.futex.fetch_and(!F_POISONED, Ordering::Relaxed);
     }
 }
 
-impl Default for RwFutex2 {
+impl<P: Policy> RwFutex2<Shared, P> {
+    /// Initializes an unlocked instance in place and returns a reference to it.
+    ///
+    /// This is for memory that is shared with other processes (see the `shared` module).
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be valid for writes of `mem::size_of::<RwFutex2<Shared, P>>()` bytes
+    /// (a 64-bit state word and more) and aligned to `mem::align_of::<RwFutex2<Shared, P>>()`.
+    /// It has to stay mapped for `'a` and nobody may be using the lock while it's initialized.
+    pub unsafe fn init_at<'a>(ptr: *mut RwFutex2<Shared, P>) -> &'a RwFutex2<Shared, P> {
+        ptr::write(ptr, RwFutex2::default());
+        &*ptr
+    }
+
+    /// Returns a reference to an instance that was initialized elsewhere.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to an instance initialized with `init_at` (possibly
+    /// by another process) that stays mapped for `'a`.
+    pub unsafe fn from_ptr<'a>(ptr: *const RwFutex2<Shared, P>) -> &'a RwFutex2<Shared, P> {
+        &*ptr
+    }
+}
+
+impl<S: Scope, P: Policy> Default for RwFutex2<S, P> {
     /// Creates a new instance.
-    fn default() -> RwFutex2 {
+    fn default() -> RwFutex2<S, P> {
         RwFutex2 {
-            futex: AtomicU32::new(0),
+            futex: AtomicU64::new(0),
+            next_handoff: AtomicU64::new(0),
+            scope: PhantomData,
+            policy: PhantomData,
         }
     }
 }
 
-impl Debug for RwFutex2 {
+impl<S: Scope, P: Policy> Debug for RwFutex2<S, P> {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
-        write!(f, "RwFutex@{:p} (=0x{:08x})", &self.futex as *const _,
+        write!(f, "RwFutex@{:p} (=0x{:016x})", &self.futex as *const _,
                self.futex.load(Ordering::SeqCst))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+    use lock_wrappers::raw::RwLock;
+    use traits::{TimedRwLock, TryRwLock};
+    use super::*;
+
+    #[test]
+    fn abandon_during_handoff() {
+        // a release just handed the lock over to the only waiting writer,
+        // which times out before it's woken up
+        let futex = RwFutex2::<Private>::new();
+        futex.futex.store(ONE_WRITER | F_HANDOFF, Ordering::Relaxed);
+        assert_eq!(futex.try_acquire_write(), None);
+
+        // it takes the lock after all instead of leaving it to newcomers
+        // (which could take it while the hand-off turns into a shove for a third writer)
+        assert!(futex.abandon_write(false));
+        assert_eq!(futex.try_acquire_write(), None);
+        futex.hand_over();
+        assert_eq!(futex.try_acquire_write_for(Duration::from_millis(10)), None);
+        futex.release_write(());
+        assert_eq!(futex.futex.load(Ordering::Relaxed), 0);
+
+        // same but with readers still around: it passes the lock on right away
+        futex.futex.store(ONE_READER | ONE_WRITER | F_HANDOFF, Ordering::Relaxed);
+        assert!(!futex.abandon_write(false));
+        assert_eq!(futex.futex.load(Ordering::Relaxed), ONE_READER);
+        futex.release_read(());
+        futex.acquire_write();
+        futex.release_write(());
+        assert_eq!(futex.futex.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,90 @@
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use error::OwnerDiedError;
+use raw;
+
+/// The result of acquiring a robust lock.
+pub type RobustLockResult<G> = Result<G, OwnerDiedError<G>>;
+
+/// A mutex that survives the death of its owner.
+///
+/// See `raw::RobustMutex` for the details.
+pub struct RobustMutex<T> {
+    mutex: raw::RobustMutex,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for RobustMutex<T> { }
+unsafe impl<T: Send> Sync for RobustMutex<T> { }
+
+impl<T> RobustMutex<T> {
+    pub fn new(t: T) -> RobustMutex<T> {
+        RobustMutex {
+            mutex: raw::RobustMutex::new(),
+            data: UnsafeCell::new(t),
+        }
+    }
+
+    /// Acquires the lock.
+    ///
+    /// If the previous owner died while holding it, the lock is acquired
+    /// anyways but the guard is wrapped in an error.
+    pub fn lock(&self) -> RobustLockResult<RobustMutexGuard<'_, T>> {
+        let res = self.mutex.lock();
+        self.guard(res)
+    }
+
+    /// Attempts to acquire the lock without blocking.
+    pub fn try_lock(&self) -> Option<RobustLockResult<RobustMutexGuard<'_, T>>> {
+        self.mutex.try_lock().map(|res| self.guard(res))
+    }
+
+    fn guard(&self, res: Result<(), OwnerDiedError<()>>) -> RobustLockResult<RobustMutexGuard<'_, T>> {
+        let guard = RobustMutexGuard {
+            mutex: self,
+            _not_send: PhantomData,
+        };
+        match res {
+            Ok(()) => Ok(guard),
+            Err(_) => Err(OwnerDiedError::new(guard)),
+        }
+    }
+}
+
+impl<T: Default> Default for RobustMutex<T> {
+    fn default() -> RobustMutex<T> {
+        RobustMutex::new(Default::default())
+    }
+}
+
+/// The guard of a `RobustMutex`.
+///
+/// It can't be sent to other threads as the lock has to be released by its owner.
+#[must_use]
+pub struct RobustMutexGuard<'a, T: 'a> {
+    mutex: &'a RobustMutex<T>,
+    _not_send: PhantomData<*const ()>,
+}
+
+unsafe impl<'a, T: Sync + 'a> Sync for RobustMutexGuard<'a, T> { }
+
+impl<'a, T: 'a> Drop for RobustMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.mutex.unlock();
+    }
+}
+
+impl<'a, T: 'a> Deref for RobustMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T: 'a> DerefMut for RobustMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
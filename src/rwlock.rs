@@ -0,0 +1,236 @@
+//! A reader-writer lock wrapper generic over the raw lock.
+//!
+//! This mirrors `lock_wrappers::RwLock` but additionally exposes
+//! the extended capabilities of the raw locks in this crate.
+
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+use lock_wrappers::raw::RwLock as RawRwLock;
+use traits::{DowngradableRwLock, TryRwLock, UpgradableRwLock};
+
+pub struct RwLock<L: RawRwLock, T> {
+    rwlock: L,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<L: RawRwLock, T: Send + Sync> Send for RwLock<L, T> {}
+unsafe impl<L: RawRwLock, T: Send + Sync> Sync for RwLock<L, T> {}
+
+impl<L: RawRwLock, T> RwLock<L, T> {
+    pub fn new_custom(l: L, t: T) -> RwLock<L, T> {
+        RwLock {
+            rwlock: l,
+            data: UnsafeCell::new(t),
+        }
+    }
+
+    /// Returns the raw lock.
+    pub(crate) fn raw(&self) -> &L {
+        &self.rwlock
+    }
+
+    /// Consumes the lock, returning the data.
+    pub(crate) fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+
+    /// Returns a mutable reference to the data.
+    ///
+    /// No locking is needed since this borrows the lock mutably.
+    pub(crate) fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    #[inline]
+    pub fn read(&self) -> RwLockReadGuard<'_, L, T> {
+        RwLockReadGuard {
+            state: Some(self.rwlock.acquire_read()),
+            rwlock: self,
+        }
+    }
+
+    #[inline]
+    pub fn write(&self) -> RwLockWriteGuard<'_, L, T> {
+        RwLockWriteGuard {
+            state: Some(self.rwlock.acquire_write()),
+            rwlock: self,
+        }
+    }
+}
+
+impl<L: TryRwLock, T> RwLock<L, T> {
+    /// Attempts to acquire a read lock without blocking.
+    #[inline]
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, L, T>> {
+        self.rwlock.try_acquire_read().map(|x| RwLockReadGuard {
+            state: Some(x),
+            rwlock: self,
+        })
+    }
+
+    /// Attempts to acquire a write lock without blocking.
+    #[inline]
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, L, T>> {
+        self.rwlock.try_acquire_write().map(|x| RwLockWriteGuard {
+            state: Some(x),
+            rwlock: self,
+        })
+    }
+}
+
+impl<L: UpgradableRwLock, T> RwLock<L, T> {
+    /// Acquires an upgradable read lock.
+    ///
+    /// Only one thread at a time can hold one, but regular readers can share
+    /// the lock with it. It can be upgraded to a write lock later on without
+    /// giving anyone else the chance to write in between.
+    #[inline]
+    pub fn upgradable_read(&self) -> RwLockUpgradableReadGuard<'_, L, T> {
+        RwLockUpgradableReadGuard {
+            state: Some(self.rwlock.acquire_upgradable()),
+            rwlock: self,
+        }
+    }
+}
+
+impl<L: RawRwLock + Default, T> RwLock<L, T> {
+    pub fn new(t: T) -> RwLock<L, T> {
+        RwLock {
+            rwlock: L::default(),
+            data: UnsafeCell::new(t),
+        }
+    }
+}
+
+impl<L: RawRwLock + Default, T: Default> Default for RwLock<L, T> {
+    fn default() -> RwLock<L, T> {
+        RwLock::new(Default::default())
+    }
+}
+
+#[must_use]
+pub struct RwLockReadGuard<'a, L: RawRwLock + 'a, T: 'a> {
+    state: Option<L::ReadLockState>,
+    rwlock: &'a RwLock<L, T>,
+}
+
+#[must_use]
+pub struct RwLockWriteGuard<'a, L: RawRwLock + 'a, T: 'a> {
+    state: Option<L::WriteLockState>,
+    rwlock: &'a RwLock<L, T>,
+}
+
+#[must_use]
+pub struct RwLockUpgradableReadGuard<'a, L: UpgradableRwLock + 'a, T: 'a> {
+    state: Option<L::UpgradableLockState>,
+    rwlock: &'a RwLock<L, T>,
+}
+
+impl<'a, L: UpgradableRwLock + 'a, T: 'a> RwLockUpgradableReadGuard<'a, L, T> {
+    /// Turns this into a write lock, blocking until all other readers are gone.
+    pub fn upgrade(mut self) -> RwLockWriteGuard<'a, L, T> {
+        let state = self.state.take().unwrap();
+        RwLockWriteGuard {
+            state: Some(self.rwlock.rwlock.upgrade(state)),
+            rwlock: self.rwlock,
+        }
+    }
+
+    /// Attempts to turn this into a write lock without blocking.
+    ///
+    /// Gives the guard back if there are other readers.
+    pub fn try_upgrade(mut self) -> Result<RwLockWriteGuard<'a, L, T>, Self> {
+        let state = self.state.take().unwrap();
+        match self.rwlock.rwlock.try_upgrade(state) {
+            Ok(x) => Ok(RwLockWriteGuard {
+                state: Some(x),
+                rwlock: self.rwlock,
+            }),
+            Err(x) => {
+                self.state = Some(x);
+                Err(self)
+            }
+        }
+    }
+
+    /// Turns this into a regular read lock, letting the next upgradable reader in.
+    pub fn downgrade(mut self) -> RwLockReadGuard<'a, L, T> {
+        let state = self.state.take().unwrap();
+        RwLockReadGuard {
+            state: Some(self.rwlock.rwlock.downgrade_upgradable(state)),
+            rwlock: self.rwlock,
+        }
+    }
+}
+
+impl<'a, L: RawRwLock + 'a, T: 'a> Drop for RwLockReadGuard<'a, L, T> {
+    fn drop(&mut self) {
+        self.rwlock.rwlock.release_read(self.state.take().unwrap());
+    }
+}
+
+impl<'a, L: RawRwLock + 'a, T: 'a> RwLockWriteGuard<'a, L, T> {
+    /// Returns the raw lock.
+    pub(crate) fn raw(&self) -> &'a L {
+        &self.rwlock.rwlock
+    }
+}
+
+impl<'a, L: DowngradableRwLock + 'a, T: 'a> RwLockWriteGuard<'a, L, T> {
+    /// Turns this into a read lock without letting another writer in between.
+    pub fn downgrade(mut self) -> RwLockReadGuard<'a, L, T> {
+        let state = self.state.take().unwrap();
+        RwLockReadGuard {
+            state: Some(self.rwlock.rwlock.downgrade(state)),
+            rwlock: self.rwlock,
+        }
+    }
+}
+
+impl<'a, L: RawRwLock + 'a, T: 'a> Drop for RwLockWriteGuard<'a, L, T> {
+    fn drop(&mut self) {
+        // (gone if we were downgraded)
+        if let Some(state) = self.state.take() {
+            self.rwlock.rwlock.release_write(state);
+        }
+    }
+}
+
+impl<'a, L: UpgradableRwLock + 'a, T: 'a> Drop for RwLockUpgradableReadGuard<'a, L, T> {
+    fn drop(&mut self) {
+        // (gone if we were upgraded or downgraded)
+        if let Some(state) = self.state.take() {
+            self.rwlock.rwlock.release_upgradable(state);
+        }
+    }
+}
+
+impl<'a, L: RawRwLock + 'a, T: 'a> Deref for RwLockReadGuard<'a, L, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.data.get() }
+    }
+}
+
+impl<'a, L: RawRwLock + 'a, T: 'a> Deref for RwLockWriteGuard<'a, L, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.data.get() }
+    }
+}
+
+impl<'a, L: UpgradableRwLock + 'a, T: 'a> Deref for RwLockUpgradableReadGuard<'a, L, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.data.get() }
+    }
+}
+
+impl<'a, L: RawRwLock + 'a, T: 'a> DerefMut for RwLockWriteGuard<'a, L, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.rwlock.data.get() }
+    }
+}
@@ -0,0 +1,215 @@
+//! Locks in memory that is shared between processes.
+//!
+//! Locks on `Shared` futexes work across processes as long as they live
+//! in a shared mapping. These helpers create such a mapping backed by a `memfd` which can be passed on to other
+//! processes, either by `fork`ing or by sending the file descriptor.
+//!
+//! To put a lock anywhere else in shared memory (e.g. an `mmap`ed file or next to
+//! other data), set it up with `init_at` of `raw::Futex<Shared>`, `raw::RwFutex2<Shared>`
+//! or `raw::RobustMutex` and have the other processes use `from_ptr`. All of them
+//! need to run the same build of this crate so they agree on the layout.
+
+use std::{io, mem, ptr};
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::ops::Deref;
+use std::os::unix::io::{AsRawFd, RawFd};
+use libc::{self, c_char, c_void, off_t};
+use {mutex, raw};
+#[cfg(target_has_atomic = "64")]
+use rwlock;
+
+/// A `memfd`-backed shared mapping holding a single `X`.
+struct Region<X> {
+    fd: RawFd,
+    ptr: *mut X,
+}
+
+impl<X> Region<X> {
+    /// Creates a new region. The contents are zeroed.
+    fn create() -> io::Result<Region<X>> {
+        let fd = unsafe { libc::memfd_create(b"futex\0".as_ptr() as *const c_char, libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if unsafe { libc::ftruncate(fd, Region::<X>::len() as off_t) } < 0 {
+            let err = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(err);
+        }
+        Region::map(fd)
+    }
+
+    /// Maps an existing region, taking ownership of `fd` (even on failure).
+    fn open(fd: RawFd) -> io::Result<Region<X>> {
+        let mut stat: libc::stat = unsafe { mem::zeroed() };
+        if unsafe { libc::fstat(fd, &mut stat) } < 0 {
+            let err = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(err);
+        }
+        if (stat.st_size as u64) < Region::<X>::len() as u64 {
+            unsafe { libc::close(fd) };
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "shared region is too small"));
+        }
+        Region::map(fd)
+    }
+
+    fn map(fd: RawFd) -> io::Result<Region<X>> {
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), Region::<X>::len(), libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_SHARED, fd, 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            let err = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(err);
+        }
+        Ok(Region { fd, ptr: ptr as *mut X })
+    }
+
+    // mmap refuses empty mappings
+    fn len() -> usize {
+        mem::size_of::<X>().max(1)
+    }
+}
+
+impl<X> Drop for Region<X> {
+    fn drop(&mut self) {
+        // the value itself is never dropped: other processes may still use it
+        unsafe {
+            libc::munmap(self.ptr as *mut c_void, Region::<X>::len());
+            libc::close(self.fd);
+        }
+    }
+}
+
+macro_rules! shared_lock {
+    ($name:ident, $lock:ty, $what:expr, $new:expr) => {
+        #[doc = concat!("A ", $what, " in a `memfd`-backed mapping that can be shared with other processes.")]
+        ///
+        /// It dereferences to the lock itself. Children created with `fork`
+        /// inherit the mapping; unrelated processes can receive the file
+        /// descriptor (see `as_raw_fd`) and use `from_fd`.
+        ///
+        /// The data must not contain pointers since they are meaningless in
+        /// other processes, and it is never dropped. All processes have to run
+        /// the same build of this crate so they agree on the layout.
+        pub struct $name<T: Copy> {
+            region: Region<$lock>,
+        }
+
+        unsafe impl<T: Copy + Send> Send for $name<T> { }
+        unsafe impl<T: Copy + Send + Sync> Sync for $name<T> { }
+
+        impl<T: Copy> $name<T> {
+            /// Creates a new shared mapping holding `t`.
+            pub fn new(t: T) -> io::Result<$name<T>> {
+                let region = Region::create()?;
+                unsafe { ptr::write(region.ptr, $new(t)) };
+                Ok($name { region })
+            }
+
+            /// Maps a region that was created by `new` in another process.
+            ///
+            /// Takes ownership of `fd`, which is closed even if this fails.
+            ///
+            /// # Safety
+            ///
+            /// `fd` must refer to a region created by `new` with the same `T`.
+            pub unsafe fn from_fd(fd: RawFd) -> io::Result<$name<T>> {
+                Ok($name { region: Region::open(fd)? })
+            }
+        }
+
+        impl<T: Copy> Deref for $name<T> {
+            type Target = $lock;
+
+            fn deref(&self) -> &$lock {
+                unsafe { &*self.region.ptr }
+            }
+        }
+
+        impl<T: Copy> AsRawFd for $name<T> {
+            fn as_raw_fd(&self) -> RawFd {
+                self.region.fd
+            }
+        }
+
+        impl<T: Copy> Debug for $name<T> {
+            fn fmt(&self, f: &mut Formatter) -> FmtResult {
+                f.debug_struct(stringify!($name))
+                    .field("fd", &self.region.fd)
+                    .finish_non_exhaustive()
+            }
+        }
+    }
+}
+
+shared_lock!(SharedMutex, mutex::Mutex<raw::SharedMutex, T>, "mutex",
+             |t| mutex::Mutex::new(raw::SharedMutex::new(), t));
+#[cfg(target_has_atomic = "64")]
+shared_lock!(SharedRwLock, rwlock::RwLock<raw::SharedRwLock, T>, "reader-writer lock",
+             rwlock::RwLock::new);
+
+#[cfg(test)]
+mod tests {
+    // (the reader-writer lock tests need 64-bit atomics)
+    #![cfg_attr(not(target_has_atomic = "64"), allow(unused_imports))]
+    use std::os::unix::io::AsRawFd;
+    use std::panic::{self, AssertUnwindSafe};
+    use libc;
+    use super::SharedMutex;
+    #[cfg(target_has_atomic = "64")]
+    use super::SharedRwLock;
+
+    // fork and run `f` in the child, returning its pid
+    fn spawn<F: FnOnce()>(f: F) -> libc::pid_t {
+        match unsafe { libc::fork() } {
+            -1 => panic!("fork failed"),
+            0 => {
+                // never unwind into the test harness of the child
+                let ok = panic::catch_unwind(AssertUnwindSafe(f)).is_ok();
+                unsafe { libc::_exit(if ok { 0 } else { 1 }) }
+            }
+            pid => pid,
+        }
+    }
+
+    fn join(pid: libc::pid_t) {
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+    }
+
+    #[test]
+    fn shared_mutex() {
+        const N: u64 = 10000;
+        let mutex = SharedMutex::new((0u64, false)).unwrap();
+        let pids: Vec<_> = (0..4).map(|_| spawn(|| {
+            for _ in 0..N {
+                let mut guard = mutex.lock();
+                assert!(!guard.1);
+                guard.1 = true;
+                guard.0 += 1;
+                guard.1 = false;
+            }
+        })).collect();
+        for pid in pids {
+            join(pid);
+        }
+        assert_eq!(mutex.lock().0, 4 * N);
+    }
+
+    #[test]
+    #[cfg(target_has_atomic = "64")]
+    fn shared_rwlock() {
+        let lock = SharedRwLock::new(0u32).unwrap();
+        let other = unsafe { SharedRwLock::<u32>::from_fd(libc::dup(lock.as_raw_fd())) }.unwrap();
+        let guard = lock.read();
+        let pid = spawn(|| *other.write() = 42);
+        assert_eq!(*guard, 0);
+        drop(guard);
+        join(pid);
+        assert_eq!(*lock.read(), 42);
+    }
+}
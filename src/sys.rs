@@ -1,80 +1,803 @@
-use libc::{c_int, syscall, timespec};
-use std::{ptr, io, i32};
-use integer_atomics::{AtomicI32, AtomicU32};
+//! Thin wrappers around the raw futex syscall.
+//!
+//! On 32-bit architectures (except x32) this uses `futex_time64`
+//! to support timeouts past 2038. Kernels before 5.1 don't have it, so there
+//! the old `futex` syscall is used instead, which can't wait past 2038.
+//!
+//! Some of those (like mips and powerpc) lack 64-bit atomics, so they only
+//! get the mutexes: `raw::RwFutex2` and the locks built on it need them.
+
+use libc::{c_int, c_long, c_uint, syscall, timespec, clock_gettime, CLOCK_MONOTONIC};
+use std::{mem, ptr, thread};
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::sync::Once;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicI32, AtomicU32};
+#[cfg(all(target_pointer_width = "32", any(target_arch = "x86", target_arch = "arm", target_arch = "mips",
+                                           target_arch = "powerpc", target_arch = "sparc")))]
+use std::sync::atomic::{AtomicBool, Ordering};
+use error::Error;
 
 const FUTEX_WAIT: c_int = 0;
 const FUTEX_WAKE: c_int = 1;
+const FUTEX_REQUEUE: c_int = 3;
+const FUTEX_CMP_REQUEUE: c_int = 4;
+const FUTEX_WAKE_OP: c_int = 5;
+const FUTEX_LOCK_PI: c_int = 6;
+const FUTEX_UNLOCK_PI: c_int = 7;
+const FUTEX_TRYLOCK_PI: c_int = 8;
 const FUTEX_WAIT_BITSET: c_int = 9;
 const FUTEX_WAKE_BITSET: c_int = 10;
+const FUTEX_LOCK_PI2: c_int = 13;
+
+const FUTEX_PRIVATE_FLAG: c_int = 128;
+const FUTEX_CLOCK_REALTIME: c_int = 256;
+const FUTEX_BITSET_MATCH_ANY: c_int = -1;
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Whether a futex is private to this process or shared with others.
+///
+/// Private futexes are cheaper: the kernel can identify them by their
+/// address alone instead of having to look up (and pin) the page behind
+/// them to find out what shared memory object they live in.
+///
+/// This is implemented by `Private` and `Shared` only.
+pub trait Scope: sealed::Sealed + Send + Sync + 'static {
+    /// The flags to add to the futex operation.
+    #[doc(hidden)]
+    const FLAGS: c_int;
+    /// The flags to add to a `futex_waitv` entry.
+    #[doc(hidden)]
+    const FUTEX2_FLAGS: u32;
+}
+
+/// A futex that is only ever used within one process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Private;
+
+/// A futex that may be used by several processes through shared memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Shared;
+
+impl sealed::Sealed for Private {}
+impl sealed::Sealed for Shared {}
+
+impl Scope for Private {
+    const FLAGS: c_int = FUTEX_PRIVATE_FLAG;
+    const FUTEX2_FLAGS: u32 = FUTEX2_PRIVATE;
+}
+
+impl Scope for Shared {
+    const FLAGS: c_int = 0;
+    const FUTEX2_FLAGS: u32 = 0;
+}
+
+// The 32-bit architectures only take a 64-bit timespec through futex_time64
+// (Linux 5.1+) which libc doesn't know about on most of them. x32 is fine with SYS_futex.
+#[cfg(any(target_pointer_width = "64", target_arch = "x86_64"))]
+const SYS_FUTEX: c_long = libc::SYS_futex;
+#[cfg(all(target_pointer_width = "32", not(any(target_arch = "x86_64", target_arch = "mips", target_arch = "mips32r6",
+                                               target_arch = "mips64", target_arch = "mips64r6"))))]
+const SYS_FUTEX: c_long = 422; // SYS_futex_time64
+#[cfg(any(target_arch = "mips", target_arch = "mips32r6"))]
+const SYS_FUTEX: c_long = 4000 + 422; // SYS_futex_time64 (o32)
+#[cfg(all(target_pointer_width = "32", any(target_arch = "mips64", target_arch = "mips64r6")))]
+const SYS_FUTEX: c_long = 6000 + 422; // SYS_futex_time64 (n32)
+
+// Where futex_time64 may be missing (because the kernel predates it),
+// libc's SYS_futex is the old one with the 32-bit timespec.
+// (newer ports like riscv32 never had anything but futex_time64)
+#[cfg(all(target_pointer_width = "32", any(target_arch = "x86", target_arch = "arm", target_arch = "mips",
+                                           target_arch = "powerpc", target_arch = "sparc")))]
+static NO_FUTEX_TIME64: AtomicBool = AtomicBool::new(false);
+
+// futex_waitv is new enough to have the same number everywhere (but libc may not know it),
+// except that the mips ABIs and x32 add their usual offsets to it
+#[cfg(not(any(target_arch = "mips", target_arch = "mips32r6", target_arch = "mips64", target_arch = "mips64r6",
+              all(target_arch = "x86_64", target_pointer_width = "32"))))]
+const SYS_FUTEX_WAITV: c_long = 449;
+#[cfg(all(target_arch = "x86_64", target_pointer_width = "32"))]
+const SYS_FUTEX_WAITV: c_long = 0x40000000 + 449; // __X32_SYSCALL_BIT
+#[cfg(any(target_arch = "mips", target_arch = "mips32r6"))]
+const SYS_FUTEX_WAITV: c_long = 4000 + 449; // o32
+#[cfg(all(target_pointer_width = "64", any(target_arch = "mips64", target_arch = "mips64r6")))]
+const SYS_FUTEX_WAITV: c_long = 5000 + 449; // n64
+#[cfg(all(target_pointer_width = "32", any(target_arch = "mips64", target_arch = "mips64r6")))]
+const SYS_FUTEX_WAITV: c_long = 6000 + 449; // n32
+
+const FUTEX2_SIZE_U32: u32 = 0x02;
+const FUTEX2_PRIVATE: u32 = 128;
+
+/// The maximum number of futex words `futex_waitv` can wait on at once.
+pub const FUTEX_WAITV_MAX: usize = 128;
+
+/// `struct __kernel_timespec`, which is what `SYS_FUTEX` takes on every architecture
+/// (unlike `libc::timespec` which follows the `time_t` of the C library).
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Timespec {
+    tv_sec: i64,
+    tv_nsec: i64,
+}
+
+#[inline(always)]
+#[cfg(not(all(target_pointer_width = "32", any(target_arch = "x86", target_arch = "arm", target_arch = "mips",
+                                               target_arch = "powerpc", target_arch = "sparc"))))]
+unsafe fn do_futex(uaddr: *mut c_int, futex_op: c_int, val: c_int, timeout: *const Timespec, uaddr2: *mut c_int, val3: c_int) -> c_int {
+    syscall(SYS_FUTEX, uaddr, futex_op, val, timeout, uaddr2, val3) as c_int
+}
 
 #[inline(always)]
-unsafe fn do_futex(uaddr: *mut c_int, futex_op: c_int, val: c_int, timeout: *const timespec, uaddr2: *mut c_int, val3: c_int) -> c_int {
-    syscall(202/*SYS_futex*/, uaddr, futex_op, val, timeout, uaddr2, val3) as i32
+#[cfg(all(target_pointer_width = "32", any(target_arch = "x86", target_arch = "arm", target_arch = "mips",
+                                           target_arch = "powerpc", target_arch = "sparc")))]
+unsafe fn do_futex(uaddr: *mut c_int, futex_op: c_int, val: c_int, timeout: *const Timespec, uaddr2: *mut c_int, val3: c_int) -> c_int {
+    if !NO_FUTEX_TIME64.load(Ordering::Relaxed) {
+        let ret = syscall(SYS_FUTEX, uaddr, futex_op, val, timeout, uaddr2, val3) as c_int;
+        if ret != -1 || Error::last() != Error::Unsupported || !futex_time64_missing(uaddr) {
+            return ret;
+        }
+    }
+    do_futex_time32(uaddr, futex_op, val, timeout, uaddr2, val3)
+}
+
+/// Finds out whether `ENOSYS` means that there's no `futex_time64` at all
+/// (rather than that the operation isn't supported) and remembers it.
+#[cold]
+#[cfg(all(target_pointer_width = "32", any(target_arch = "x86", target_arch = "arm", target_arch = "mips",
+                                           target_arch = "powerpc", target_arch = "sparc")))]
+unsafe fn futex_time64_missing(uaddr: *mut c_int) -> bool {
+    // (waking nobody is harmless and works everywhere futexes do, leaving errno as is)
+    if syscall(SYS_FUTEX, uaddr, FUTEX_WAKE | FUTEX_PRIVATE_FLAG, 0, ptr::null::<Timespec>(), ptr::null_mut::<c_int>(), 0) != -1 {
+        return false;
+    }
+    NO_FUTEX_TIME64.store(true, Ordering::Relaxed);
+    true
+}
+
+/// `struct old_timespec32`, which is what the old `futex` syscall takes.
+#[repr(C)]
+#[cfg(all(target_pointer_width = "32", any(target_arch = "x86", target_arch = "arm", target_arch = "mips",
+                                           target_arch = "powerpc", target_arch = "sparc")))]
+struct Timespec32 {
+    tv_sec: i32,
+    tv_nsec: i32,
+}
+
+/// Like `do_futex` but through the old syscall, clamping timeouts to what fits.
+#[cfg(all(target_pointer_width = "32", any(target_arch = "x86", target_arch = "arm", target_arch = "mips",
+                                           target_arch = "powerpc", target_arch = "sparc")))]
+unsafe fn do_futex_time32(uaddr: *mut c_int, futex_op: c_int, val: c_int, timeout: *const Timespec, uaddr2: *mut c_int, val3: c_int) -> c_int {
+    let timeout32;
+    let timeout = match futex_op & !(FUTEX_PRIVATE_FLAG | FUTEX_CLOCK_REALTIME) {
+        // (the other operations take an integer there, if anything)
+        FUTEX_WAIT | FUTEX_WAIT_BITSET | FUTEX_LOCK_PI | FUTEX_LOCK_PI2 if !timeout.is_null() => {
+            timeout32 = Timespec32 {
+                tv_sec: (*timeout).tv_sec.min(i32::MAX as i64) as i32,
+                tv_nsec: (*timeout).tv_nsec as i32,
+            };
+            &timeout32 as *const Timespec32 as *const Timespec
+        }
+        _ => timeout,
+    };
+    syscall(libc::SYS_futex, uaddr, futex_op, val, timeout, uaddr2, val3) as c_int
+}
+
+fn duration_to_timespec(d: Duration) -> Timespec {
+    if d.as_secs() > i64::MAX as u64 {
+        // close enough to forever
+        return Timespec { tv_sec: i64::MAX, tv_nsec: 0 };
+    }
+    Timespec {
+        tv_sec: d.as_secs() as i64,
+        tv_nsec: d.subsec_nanos() as i64,
+    }
+}
+
+fn timespec_add(a: Timespec, b: Timespec) -> Timespec {
+    let mut sec = a.tv_sec.saturating_add(b.tv_sec);
+    let mut nsec = a.tv_nsec + b.tv_nsec;
+    if nsec >= 1_000_000_000 {
+        nsec -= 1_000_000_000;
+        sec = sec.saturating_add(1);
+    }
+    Timespec { tv_sec: sec, tv_nsec: nsec }
+}
+
+/// Translates an `Instant` into an absolute `CLOCK_MONOTONIC` timestamp.
+///
+/// `Instant` is opaque, so this goes through the remaining time.
+fn monotonic_timespec(deadline: Instant) -> Timespec {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    timespec_add(monotonic_now(), duration_to_timespec(remaining))
+}
+
+fn monotonic_now() -> Timespec {
+    let mut now: timespec = unsafe { mem::zeroed() };
+    let ret = unsafe { clock_gettime(CLOCK_MONOTONIC, &mut now) };
+    assert_eq!(ret, 0);
+    Timespec { tv_sec: now.tv_sec as i64, tv_nsec: now.tv_nsec as i64 }
+}
+
+/// The current `CLOCK_MONOTONIC` time in nanoseconds.
+///
+/// Unlike an `Instant`, this means the same in every process.
+pub(crate) fn monotonic_nanos() -> u64 {
+    let now = monotonic_now();
+    now.tv_sec as u64 * 1_000_000_000 + now.tv_nsec as u64
+}
+
+fn realtime_timespec(deadline: SystemTime) -> Timespec {
+    // deadlines before the epoch have passed anyways
+    duration_to_timespec(deadline.duration_since(UNIX_EPOCH).unwrap_or_default())
+}
+
+thread_local!(static TID: Cell<u32> = const { Cell::new(0) });
+
+extern "C" fn forget_tid() {
+    // the child of a fork is a new thread that inherited our cache
+    let _ = TID.try_with(|tid| tid.set(0));
+}
+
+/// Returns the (cached) thread ID of the calling thread,
+/// which is what the kernel expects to find in the word of an owned PI futex.
+pub(crate) fn current_tid() -> u32 {
+    TID.with(|tid| {
+        if tid.get() == 0 {
+            static ATFORK: Once = Once::new();
+            ATFORK.call_once(|| unsafe {
+                libc::pthread_atfork(None, None, Some(forget_tid));
+            });
+            tid.set(unsafe { syscall(libc::SYS_gettid) } as u32);
+        }
+        tid.get()
+    })
+}
+
+#[inline(always)]
+fn wait_result(ret: c_int) -> Result<(), Error> {
+    if ret == -1 {
+        Err(Error::last())
+    } else {
+        Ok(())
+    }
+}
+
+/// Interprets the outcome of a wait for the locks in this crate.
+///
+/// They recheck their state after every wakeup (spurious or not), so all
+/// that matters is whether `deadline` has passed, in which case this returns `false`.
+///
+/// If futexes are unavailable (e.g. a seccomp filter rejects them), waiting
+/// degrades to yielding the CPU: the locks keep working, they just spin instead
+/// of sleeping. This assumes that waits and wakes are rejected alike.
+/// Any other error means that the lock is broken, so this panics.
+pub(crate) fn waited(res: Result<(), Error>, deadline: Option<Instant>) -> bool {
+    match res {
+        Ok(()) | Err(Error::WouldBlock) | Err(Error::Interrupted) => true,
+        Err(Error::TimedOut) => false,
+        Err(ref e) if e.is_unavailable() => {
+            thread::yield_now();
+            deadline.is_none_or(|deadline| Instant::now() < deadline)
+        }
+        Err(e) => panic!("{}", e),
+    }
+}
+
+/// Interprets the outcome of a wake for the locks in this crate,
+/// returning the number of waiters that were woken up.
+///
+/// If futexes are unavailable, the waiters are spinning (see `waited`)
+/// so there's nobody to wake.
+pub(crate) fn woken(res: Result<i32, Error>) -> i32 {
+    match res {
+        Ok(n) => n,
+        Err(ref e) if e.is_unavailable() => 0,
+        Err(e) => panic!("{}", e),
+    }
 }
 
 #[inline(never)]
-pub fn futex_wait(futex: &AtomicI32, val: i32) -> io::Result<()> {
+pub fn futex_wait<S: Scope>(futex: &AtomicI32, val: i32) -> Result<(), Error> {
     let ret = unsafe { do_futex(futex as *const _ as *mut i32,
-                                FUTEX_WAIT,
+                                FUTEX_WAIT | S::FLAGS,
                                 val,
                                 ptr::null(),
                                 ptr::null_mut(),
                                 0) };
-    match ret {
-        0 => Ok(()),
-        -1 => Err(io::Error::last_os_error()),
-        _ => unreachable!(),
-    }
+    wait_result(ret)
+}
+
+/// Like `futex_wait` but gives up after `timeout` has elapsed.
+///
+/// An expired timeout is reported as `Error::TimedOut`.
+#[inline(never)]
+pub fn futex_wait_for<S: Scope>(futex: &AtomicI32, val: i32, timeout: Duration) -> Result<(), Error> {
+    let timeout = duration_to_timespec(timeout);
+    let ret = unsafe { do_futex(futex as *const _ as *mut i32,
+                                FUTEX_WAIT | S::FLAGS,
+                                val,
+                                &timeout,
+                                ptr::null_mut(),
+                                0) };
+    wait_result(ret)
+}
+
+/// Like `futex_wait` but gives up once `deadline` (`CLOCK_MONOTONIC`) has passed.
+///
+/// An expired deadline is reported as `Error::TimedOut`.
+#[inline(never)]
+pub fn futex_wait_until<S: Scope>(futex: &AtomicI32, val: i32, deadline: Instant) -> Result<(), Error> {
+    let deadline = monotonic_timespec(deadline);
+    let ret = unsafe { do_futex(futex as *const _ as *mut i32,
+                                FUTEX_WAIT_BITSET | S::FLAGS,
+                                val,
+                                &deadline,
+                                ptr::null_mut(),
+                                FUTEX_BITSET_MATCH_ANY) };
+    wait_result(ret)
+}
+
+/// Like `futex_wait` but gives up once `deadline` (`CLOCK_REALTIME`) has passed.
+///
+/// An expired deadline is reported as `Error::TimedOut`.
+#[inline(never)]
+pub fn futex_wait_until_realtime<S: Scope>(futex: &AtomicI32, val: i32, deadline: SystemTime) -> Result<(), Error> {
+    let deadline = realtime_timespec(deadline);
+    let ret = unsafe { do_futex(futex as *const _ as *mut i32,
+                                FUTEX_WAIT_BITSET | FUTEX_CLOCK_REALTIME | S::FLAGS,
+                                val,
+                                &deadline,
+                                ptr::null_mut(),
+                                FUTEX_BITSET_MATCH_ANY) };
+    wait_result(ret)
 }
 
 #[inline(never)]
-pub fn futex_wake(futex: &AtomicI32, count: i32) -> io::Result<i32> {
+pub fn futex_wake<S: Scope>(futex: &AtomicI32, count: i32) -> Result<i32, Error> {
     let ret = unsafe { do_futex(futex as *const _ as *mut i32,
-                                FUTEX_WAKE,
+                                FUTEX_WAKE | S::FLAGS,
                                 count,
                                 ptr::null(),
                                 ptr::null_mut(),
                                 0) };
     if ret == -1 {
-        Err(io::Error::last_os_error())
+        Err(Error::last())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Wakes up to `count` waiters on `futex` and moves up to `requeue`
+/// of the remaining ones over to wait on `target` instead.
+///
+/// Returns the number of waiters that were woken up.
+#[inline(never)]
+pub fn futex_requeue<S: Scope>(futex: &AtomicI32, count: i32, requeue: i32, target: &AtomicI32) -> Result<i32, Error> {
+    let ret = unsafe { do_futex(futex as *const _ as *mut i32,
+                                FUTEX_REQUEUE | S::FLAGS,
+                                count,
+                                requeue as usize as *const Timespec, // val2
+                                target as *const _ as *mut i32,
+                                0) };
+    if ret == -1 {
+        Err(Error::last())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Like `futex_requeue` but only if `futex` still holds `val`.
+///
+/// Otherwise, nothing happens and `Error::WouldBlock` is returned.
+/// Returns the number of waiters that were either woken up or requeued.
+#[inline(never)]
+pub fn futex_cmp_requeue<S: Scope>(futex: &AtomicI32, val: i32, count: i32, requeue: i32, target: &AtomicI32) -> Result<i32, Error> {
+    let ret = unsafe { do_futex(futex as *const _ as *mut i32,
+                                FUTEX_CMP_REQUEUE | S::FLAGS,
+                                count,
+                                requeue as usize as *const Timespec, // val2
+                                target as *const _ as *mut i32,
+                                val) };
+    if ret == -1 {
+        Err(Error::last())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// The operation `FUTEX_WAKE_OP` applies to the second futex word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FutexOp {
+    /// `*futex2 = arg`
+    Set(i32),
+    /// `*futex2 += arg`
+    Add(i32),
+    /// `*futex2 |= arg`
+    Or(i32),
+    /// `*futex2 &= !arg`
+    AndNot(i32),
+    /// `*futex2 ^= arg`
+    Xor(i32),
+}
+
+/// The condition on the *old* value of the second futex word
+/// that decides whether `FUTEX_WAKE_OP` wakes its waiters too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FutexCmp {
+    Eq(i32),
+    Ne(i32),
+    Lt(i32),
+    Le(i32),
+    Gt(i32),
+    Ge(i32),
+}
+
+/// An operation for `futex_wake_op`, encoded into `val3`.
+///
+/// Operands are 12 bit signed integers (-2048 to 2047) as that's all the kernel has room for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WakeOp {
+    op: FutexOp,
+    shift: bool,
+    cmp: FutexCmp,
+}
+
+impl WakeOp {
+    /// Applies `op` to the second word and wakes its waiters if its old value satisfies `cmp`.
+    pub fn new(op: FutexOp, cmp: FutexCmp) -> WakeOp {
+        WakeOp { op, shift: false, cmp }
+    }
+
+    /// Makes the operand of the operation a bit number (i.e. `1 << arg`) instead.
+    pub fn shifted(self) -> WakeOp {
+        WakeOp { shift: true, ..self }
+    }
+
+    /// Encodes this operation for the kernel.
+    pub fn encode(&self) -> i32 {
+        fn arg(x: i32) -> u32 {
+            assert!((-2048..2048).contains(&x), "futex op operand out of range");
+            (x as u32) & 0xfff
+        }
+
+        let (op, oparg) = match self.op {
+            FutexOp::Set(x) => (0, x),
+            FutexOp::Add(x) => (1, x),
+            FutexOp::Or(x) => (2, x),
+            FutexOp::AndNot(x) => (3, x),
+            FutexOp::Xor(x) => (4, x),
+        };
+        let (cmp, cmparg) = match self.cmp {
+            FutexCmp::Eq(x) => (0, x),
+            FutexCmp::Ne(x) => (1, x),
+            FutexCmp::Lt(x) => (2, x),
+            FutexCmp::Le(x) => (3, x),
+            FutexCmp::Gt(x) => (4, x),
+            FutexCmp::Ge(x) => (5, x),
+        };
+        if self.shift {
+            assert!((0..32).contains(&oparg), "futex op shift out of range");
+        }
+        let op = if self.shift { op | 8 } else { op };
+
+        ((op << 28) | (cmp << 24) | (arg(oparg) << 12) | arg(cmparg)) as i32
+    }
+}
+
+/// Atomically applies `op` to `futex2`, wakes up to `count` waiters on `futex`
+/// and, if the old value of `futex2` satisfies the condition, also up to `count2` waiters on `futex2`.
+///
+/// Returns the total number of waiters that were woken up.
+#[inline(never)]
+pub fn futex_wake_op<S: Scope>(futex: &AtomicI32, count: i32, futex2: &AtomicI32, count2: i32, op: WakeOp) -> Result<i32, Error> {
+    let ret = unsafe { do_futex(futex as *const _ as *mut i32,
+                                FUTEX_WAKE_OP | S::FLAGS,
+                                count,
+                                count2 as usize as *const Timespec, // val2
+                                futex2 as *const _ as *mut i32,
+                                op.encode()) };
+    if ret == -1 {
+        Err(Error::last())
     } else {
         Ok(ret)
     }
 }
 
+/// Acquires the PI futex `futex`, blocking until `deadline` (`CLOCK_REALTIME`) at most.
+///
+/// This is only the slow path: the kernel takes care of queueing us up
+/// and boosting the priority of the owner whose TID it finds in the futex word.
 #[inline(never)]
-pub fn futex_wait_bitset(futex: &AtomicU32, val: u32, mask: i32) {
+pub fn futex_lock_pi<S: Scope>(futex: &AtomicU32, deadline: Option<SystemTime>) -> Result<(), Error> {
+    let deadline = deadline.map(realtime_timespec);
+    let ret = unsafe { do_futex(futex as *const _ as *mut i32,
+                                FUTEX_LOCK_PI | S::FLAGS,
+                                0,
+                                deadline.as_ref().map_or(ptr::null(), |x| x as *const _),
+                                ptr::null_mut(),
+                                0) };
+    wait_result(ret)
+}
+
+/// Like `futex_lock_pi` but the deadline is measured against `CLOCK_MONOTONIC` instead.
+///
+/// This needs Linux 5.14 or later (`Error::Unsupported` otherwise).
+#[inline(never)]
+pub fn futex_lock_pi2<S: Scope>(futex: &AtomicU32, deadline: Option<Instant>) -> Result<(), Error> {
+    let deadline = deadline.map(monotonic_timespec);
+    let ret = unsafe { do_futex(futex as *const _ as *mut i32,
+                                FUTEX_LOCK_PI2 | S::FLAGS,
+                                0,
+                                deadline.as_ref().map_or(ptr::null(), |x| x as *const _),
+                                ptr::null_mut(),
+                                0) };
+    wait_result(ret)
+}
+
+/// Attempts to acquire the PI futex `futex` without blocking.
+///
+/// Fails with `Error::WouldBlock` if someone else owns it.
+#[inline(never)]
+pub fn futex_trylock_pi<S: Scope>(futex: &AtomicU32) -> Result<(), Error> {
+    let ret = unsafe { do_futex(futex as *const _ as *mut i32,
+                                FUTEX_TRYLOCK_PI | S::FLAGS,
+                                0,
+                                ptr::null(),
+                                ptr::null_mut(),
+                                0) };
+    wait_result(ret)
+}
+
+/// Releases the PI futex `futex`, handing it over to the highest priority waiter.
+#[inline(never)]
+pub fn futex_unlock_pi<S: Scope>(futex: &AtomicU32) -> Result<(), Error> {
+    let ret = unsafe { do_futex(futex as *const _ as *mut i32,
+                                FUTEX_UNLOCK_PI | S::FLAGS,
+                                0,
+                                ptr::null(),
+                                ptr::null_mut(),
+                                0) };
+    wait_result(ret)
+}
+
+#[inline(never)]
+pub fn futex_wait_bitset<S: Scope>(futex: &AtomicU32, val: u32, mask: i32) -> Result<(), Error> {
     assert!(mask != 0);
     let ret = unsafe { do_futex(futex as *const _ as *mut i32,
-                                FUTEX_WAIT_BITSET,
+                                FUTEX_WAIT_BITSET | S::FLAGS,
                                 val as i32,
                                 ptr::null(),
                                 ptr::null_mut(),
                                 mask) };
+    wait_result(ret)
+}
 
-    if ret == -1 {
-        match io::Error::last_os_error().kind() {
-            io::ErrorKind::WouldBlock => (),
-            io::ErrorKind::Interrupted => (),
-            _ => unreachable!(),
-        }
-    } else if ret != 0 {
-        unreachable!();
-    }
+/// Like `futex_wait_bitset` but gives up once `deadline` (`CLOCK_MONOTONIC`) has passed.
+///
+/// An expired deadline is reported as `Error::TimedOut`.
+#[inline(never)]
+pub fn futex_wait_bitset_until<S: Scope>(futex: &AtomicU32, val: u32, mask: i32, deadline: Instant) -> Result<(), Error> {
+    assert!(mask != 0);
+    let deadline = monotonic_timespec(deadline);
+    let ret = unsafe { do_futex(futex as *const _ as *mut i32,
+                                FUTEX_WAIT_BITSET | S::FLAGS,
+                                val as i32,
+                                &deadline,
+                                ptr::null_mut(),
+                                mask) };
+    wait_result(ret)
+}
+
+/// Like `futex_wait_bitset` but gives up once `deadline` (`CLOCK_REALTIME`) has passed.
+///
+/// An expired deadline is reported as `Error::TimedOut`.
+#[inline(never)]
+pub fn futex_wait_bitset_until_realtime<S: Scope>(futex: &AtomicU32, val: u32, mask: i32, deadline: SystemTime) -> Result<(), Error> {
+    assert!(mask != 0);
+    let deadline = realtime_timespec(deadline);
+    let ret = unsafe { do_futex(futex as *const _ as *mut i32,
+                                FUTEX_WAIT_BITSET | FUTEX_CLOCK_REALTIME | S::FLAGS,
+                                val as i32,
+                                &deadline,
+                                ptr::null_mut(),
+                                mask) };
+    wait_result(ret)
 }
 
 #[inline(never)]
-pub fn futex_wake_bitset(futex: &AtomicU32, count: u32, mask: i32) -> i32 {
+pub fn futex_wake_bitset<S: Scope>(futex: &AtomicU32, count: u32, mask: i32) -> Result<i32, Error> {
     assert!(mask != 0);
     let ret = unsafe { do_futex(futex as *const _ as *mut i32,
-                                FUTEX_WAKE_BITSET,
+                                FUTEX_WAKE_BITSET | S::FLAGS,
                                 count as i32,
                                 ptr::null(),
                                 ptr::null_mut(),
                                 mask) };
     if ret == -1 {
-        unreachable!();
+        Err(Error::last())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// One of the futex words `futex_waitv` waits on (`struct futex_waitv`).
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct FutexWaitv<'a> {
+    val: u64,
+    uaddr: u64,
+    flags: u32,
+    reserved: u32,
+    futex: PhantomData<&'a AtomicU32>,
+}
+
+impl<'a> FutexWaitv<'a> {
+    /// Waits for `futex` to change from `val`.
+    pub fn new<S: Scope>(futex: &'a AtomicU32, val: u32) -> FutexWaitv<'a> {
+        FutexWaitv {
+            val: val as u64,
+            uaddr: futex as *const _ as usize as u64,
+            flags: FUTEX2_SIZE_U32 | S::FUTEX2_FLAGS,
+            reserved: 0,
+            futex: PhantomData,
+        }
+    }
+}
+
+/// Waits on all of `waiters` at once until any of them is woken up,
+/// giving up once `deadline` (`CLOCK_MONOTONIC`) has passed.
+///
+/// Returns the index of the futex that was woken up. If any of them doesn't
+/// hold the expected value, this fails with `Error::WouldBlock` right away.
+/// An expired deadline is reported as `Error::TimedOut`.
+///
+/// This needs Linux 5.16 or later (`Error::Unsupported` otherwise).
+/// There can't be more than `FUTEX_WAITV_MAX` waiters.
+#[inline(never)]
+pub fn futex_waitv(waiters: &[FutexWaitv], deadline: Option<Instant>) -> Result<usize, Error> {
+    assert!(waiters.len() <= FUTEX_WAITV_MAX, "too many futexes for futex_waitv");
+    let deadline = deadline.map(monotonic_timespec);
+    let ret = unsafe { syscall(SYS_FUTEX_WAITV,
+                               waiters.as_ptr(),
+                               waiters.len() as c_uint,
+                               0 as c_uint,
+                               deadline.as_ref().map_or(ptr::null(), |x| x as *const Timespec),
+                               CLOCK_MONOTONIC) };
+    if ret == -1 {
+        Err(Error::last())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant, SystemTime};
+    use std::sync::atomic::{AtomicI32, AtomicU32, Ordering};
+    use super::*;
+
+    #[test]
+    #[cfg(all(target_pointer_width = "32", any(target_arch = "x86", target_arch = "arm", target_arch = "mips",
+                                               target_arch = "powerpc", target_arch = "sparc")))]
+    fn futex_time32() {
+        // (what happens on kernels without futex_time64)
+        let futex = AtomicI32::new(0);
+        let uaddr = &futex as *const _ as *mut i32;
+        let timeout = duration_to_timespec(Duration::from_millis(10));
+        let ret = unsafe { do_futex_time32(uaddr, FUTEX_WAIT | FUTEX_PRIVATE_FLAG, 0, &timeout, ptr::null_mut(), 0) };
+        assert_eq!((ret, Error::last()), (-1, Error::TimedOut));
+
+        // a deadline past 2038 is clamped rather than rejected
+        let deadline = Timespec { tv_sec: i64::MAX, tv_nsec: 0 };
+        let ret = unsafe { do_futex_time32(uaddr, FUTEX_WAIT_BITSET | FUTEX_PRIVATE_FLAG, 1, &deadline, ptr::null_mut(), -1) };
+        assert_eq!((ret, Error::last()), (-1, Error::WouldBlock));
+
+        // (this kernel does have futex_time64, so an ENOSYS would be about the operation)
+        unsafe { assert!(!futex_time64_missing(uaddr)) };
+    }
+
+    #[test]
+    fn wait_timeouts() {
+        let futex = AtomicI32::new(0);
+        let timeout = Duration::from_millis(50);
+
+        let start = Instant::now();
+        let err = futex_wait_for::<Private>(&futex, 0, timeout).unwrap_err();
+        assert_eq!(err, Error::TimedOut);
+        assert!(start.elapsed() >= timeout);
+
+        let err = futex_wait_until::<Private>(&futex, 0, Instant::now() + timeout).unwrap_err();
+        assert_eq!(err, Error::TimedOut);
+
+        let err = futex_wait_until_realtime::<Private>(&futex, 0, SystemTime::now() + timeout).unwrap_err();
+        assert_eq!(err, Error::TimedOut);
+
+        // a stale value is not a timeout
+        let err = futex_wait_for::<Private>(&futex, 1, timeout).unwrap_err();
+        assert_eq!(err, Error::WouldBlock);
     }
 
-    ret
+    #[test]
+    fn wait_bitset_timeouts() {
+        let futex = AtomicU32::new(0);
+        let timeout = Duration::from_millis(50);
+
+        let start = Instant::now();
+        let err = futex_wait_bitset_until::<Private>(&futex, 0, 1, start + timeout).unwrap_err();
+        assert_eq!(err, Error::TimedOut);
+        assert!(start.elapsed() >= timeout);
+
+        let err = futex_wait_bitset_until_realtime::<Private>(&futex, 0, 1, SystemTime::now() + timeout).unwrap_err();
+        assert_eq!(err, Error::TimedOut);
+
+        // deadlines in the past expire immediately
+        let err = futex_wait_bitset_until::<Private>(&futex, 0, 1, start).unwrap_err();
+        assert_eq!(err, Error::TimedOut);
+    }
+
+    #[test]
+    fn fallback() {
+        assert_eq!(Error::from_errno(libc::EAGAIN), Error::WouldBlock);
+        assert_eq!(Error::from_errno(libc::EPERM), Error::Other(libc::EPERM));
+        assert_eq!(Error::Unsupported.errno(), libc::ENOSYS);
+
+        // without futexes, waits turn into yields and wakes into no-ops
+        let past = Instant::now();
+        assert!(waited(Err(Error::Unsupported), None));
+        assert!(!waited(Err(Error::Other(libc::EPERM)), Some(past)));
+        assert!(!waited(Err(Error::TimedOut), None));
+        assert_eq!(woken(Err(Error::Unsupported)), 0);
+    }
+
+    #[test]
+    fn requeue() {
+        let futexes = Arc::new((AtomicI32::new(0), AtomicI32::new(0)));
+        let futexes2 = futexes.clone();
+        let waiter = thread::spawn(move || {
+            let _ = futex_wait::<Private>(&futexes2.0, 0);
+        });
+        thread::sleep(Duration::from_millis(50));
+
+        let (ref from, ref to) = *futexes;
+        let err = futex_cmp_requeue::<Private>(from, 1, 0, 1, to).unwrap_err();
+        assert_eq!(err, Error::WouldBlock);
+        assert_eq!(futex_cmp_requeue::<Private>(from, 0, 0, 1, to).unwrap(), 1);
+
+        // it's no longer waiting here
+        assert_eq!(futex_requeue::<Private>(from, 1, 1, to).unwrap(), 0);
+        assert_eq!(futex_wake::<Private>(to, 1).unwrap(), 1);
+        waiter.join().unwrap();
+    }
+
+    #[test]
+    fn wake_op() {
+        // FUTEX_OP(FUTEX_OP_SET, 1, FUTEX_OP_CMP_GT, 0)
+        assert_eq!(WakeOp::new(FutexOp::Set(1), FutexCmp::Gt(0)).encode(), 0x04001000);
+        // FUTEX_OP(FUTEX_OP_OR | FUTEX_OP_ARG_SHIFT, 31, FUTEX_OP_CMP_EQ, -1)
+        assert_eq!(WakeOp::new(FutexOp::Or(31), FutexCmp::Eq(-1)).shifted().encode() as u32, 0xa001ffff);
+
+        let futexes = Arc::new((AtomicI32::new(0), AtomicI32::new(2)));
+        let futexes2 = futexes.clone();
+        let waiter = thread::spawn(move || {
+            let _ = futex_wait::<Private>(&futexes2.1, 2);
+        });
+        thread::sleep(Duration::from_millis(50));
+
+        let (ref futex, ref futex2) = *futexes;
+        // old value is 2, so the condition fails
+        let op = WakeOp::new(FutexOp::Add(-1), FutexCmp::Gt(2));
+        assert_eq!(futex_wake_op::<Private>(futex, 1, futex2, 1, op).unwrap(), 0);
+        assert_eq!(futex2.load(Ordering::SeqCst), 1);
+
+        let op = WakeOp::new(FutexOp::Set(0), FutexCmp::Eq(1));
+        assert_eq!(futex_wake_op::<Private>(futex, 1, futex2, 1, op).unwrap(), 1);
+        assert_eq!(futex2.load(Ordering::SeqCst), 0);
+        waiter.join().unwrap();
+    }
 }
@@ -0,0 +1,117 @@
+use std::time::{Duration, Instant};
+use lock_wrappers::raw::{Mutex, RwLock};
+
+/// A raw mutex that can give up on acquisition after a while.
+pub trait TimedMutex: Mutex {
+    /// Attempts to acquire the lock, blocking until `deadline` at most.
+    ///
+    /// Returns `None` if the deadline passed without acquiring the lock.
+    fn try_lock_until(&self, deadline: Instant) -> Option<Self::LockState>;
+
+    /// Attempts to acquire the lock, blocking for `timeout` at most.
+    ///
+    /// Returns `None` if the timeout expired without acquiring the lock.
+    fn try_lock_for(&self, timeout: Duration) -> Option<Self::LockState> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.try_lock_until(deadline),
+            // too far in the future to even represent
+            None => Some(self.lock()),
+        }
+    }
+}
+
+/// A raw reader-writer lock that can give up on acquisition after a while.
+pub trait TimedRwLock: RwLock {
+    /// Attempts to acquire a read lock, blocking until `deadline` at most.
+    ///
+    /// Returns `None` if the deadline passed without acquiring the lock.
+    fn try_acquire_read_until(&self, deadline: Instant) -> Option<Self::ReadLockState>;
+
+    /// Attempts to acquire a write lock, blocking until `deadline` at most.
+    ///
+    /// Returns `None` if the deadline passed without acquiring the lock.
+    fn try_acquire_write_until(&self, deadline: Instant) -> Option<Self::WriteLockState>;
+
+    /// Attempts to acquire a read lock, blocking for `timeout` at most.
+    ///
+    /// Returns `None` if the timeout expired without acquiring the lock.
+    fn try_acquire_read_for(&self, timeout: Duration) -> Option<Self::ReadLockState> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.try_acquire_read_until(deadline),
+            None => Some(self.acquire_read()),
+        }
+    }
+
+    /// Attempts to acquire a write lock, blocking for `timeout` at most.
+    ///
+    /// Returns `None` if the timeout expired without acquiring the lock.
+    fn try_acquire_write_for(&self, timeout: Duration) -> Option<Self::WriteLockState> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.try_acquire_write_until(deadline),
+            None => Some(self.acquire_write()),
+        }
+    }
+}
+
+/// A raw reader-writer lock that supports non-blocking acquisition.
+pub trait TryRwLock: RwLock {
+    /// Attempts to acquire a read lock without blocking.
+    fn try_acquire_read(&self) -> Option<Self::ReadLockState>;
+
+    /// Attempts to acquire a write lock without blocking.
+    fn try_acquire_write(&self) -> Option<Self::WriteLockState>;
+}
+
+/// A raw reader-writer lock that can turn a write lock into a read lock.
+pub trait DowngradableRwLock: RwLock {
+    /// Turns a write lock into a read lock without letting a writer in between.
+    fn downgrade(&self, state: Self::WriteLockState) -> Self::ReadLockState;
+}
+
+/// A raw reader-writer lock with upgradable read locks.
+///
+/// An upgradable read lock coexists with regular readers but only one thread
+/// can hold it at a time, so it can be turned into a write lock without
+/// letting anyone else write in between.
+pub trait UpgradableRwLock: RwLock {
+    type UpgradableLockState;
+
+    /// Acquires an upgradable read lock.
+    ///
+    /// This blocks until the lock is ours.
+    fn acquire_upgradable(&self) -> Self::UpgradableLockState;
+
+    /// Releases an upgradable read lock.
+    fn release_upgradable(&self, state: Self::UpgradableLockState);
+
+    /// Turns an upgradable read lock into a write lock.
+    ///
+    /// This blocks until all other readers are gone.
+    fn upgrade(&self, state: Self::UpgradableLockState) -> Self::WriteLockState;
+
+    /// Attempts to turn an upgradable read lock into a write lock without blocking.
+    ///
+    /// Hands the upgradable read lock back if there are other readers.
+    fn try_upgrade(&self, state: Self::UpgradableLockState)
+                   -> Result<Self::WriteLockState, Self::UpgradableLockState>;
+
+    /// Turns an upgradable read lock into a regular read lock.
+    fn downgrade_upgradable(&self, state: Self::UpgradableLockState) -> Self::ReadLockState;
+}
+
+/// A raw lock that can remember that a thread panicked while holding it.
+///
+/// This is what the wrappers in `poison` build on. The flag lives in the
+/// state of the lock itself, so it's already at hand after acquiring it.
+pub trait PoisonableLock {
+    /// Marks the lock as poisoned.
+    ///
+    /// Only whoever holds the lock exclusively may do this (before releasing it).
+    fn poison(&self);
+
+    /// Whether the lock is poisoned.
+    fn is_poisoned(&self) -> bool;
+
+    /// Clears the poisoned state.
+    fn clear_poison(&self);
+}
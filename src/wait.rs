@@ -0,0 +1,100 @@
+//! Waiting on several futex words at once.
+
+use std::thread;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+use sys::{futex_waitv, futex_wake_bitset, woken, FutexWaitv, Private};
+use error::Error;
+
+/// Blocks until any of `futexes` no longer holds its value or is woken up
+/// with `wake`, giving up after `timeout` (never if `None`).
+///
+/// Returns the index of the word that woke us up. Like any futex wait, this
+/// can wake up spuriously, so always check the values again.
+/// An expired timeout is reported as `Error::TimedOut`.
+///
+/// This uses `futex_waitv` which needs Linux 5.16 or later. Without it
+/// (or if a seccomp filter rejects futexes), this falls back to polling the
+/// words while yielding the CPU, so only actual changes to them are noticed.
+///
+/// # Panics
+///
+/// Panics if `futexes` is empty or holds more than 128 words.
+pub fn wait_any(futexes: &[(&AtomicU32, u32)], timeout: Option<Duration>) -> Result<usize, Error> {
+    assert!(!futexes.is_empty(), "nothing to wait for");
+    // an overflowing deadline might as well be forever
+    let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+    let waiters: Vec<_> = futexes.iter().map(|&(futex, val)| FutexWaitv::new::<Private>(futex, val)).collect();
+    loop {
+        match futex_waitv(&waiters, deadline) {
+            Ok(i) => return Ok(i),
+            Err(Error::WouldBlock) => {
+                // the kernel doesn't tell us which one it was
+                if let Some(i) = changed(futexes) {
+                    return Ok(i);
+                }
+                // it already changed back, so there's nothing to report
+            }
+            Err(Error::Interrupted) => (),
+            Err(ref e) if e.is_unavailable() => return poll(futexes, deadline),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Returns the index of the first word that doesn't hold its value.
+fn changed(futexes: &[(&AtomicU32, u32)]) -> Option<usize> {
+    futexes.iter().position(|&(futex, val)| futex.load(Ordering::Relaxed) != val)
+}
+
+#[cold]
+fn poll(futexes: &[(&AtomicU32, u32)], deadline: Option<Instant>) -> Result<usize, Error> {
+    loop {
+        if let Some(i) = changed(futexes) {
+            return Ok(i);
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(Error::TimedOut);
+        }
+        thread::yield_now();
+    }
+}
+
+/// Wakes up to `count` threads waiting on `futex` (e.g. in `wait_any`).
+///
+/// Returns the number of threads that were woken up.
+pub fn wake(futex: &AtomicU32, count: u32) -> usize {
+    woken(futex_wake_bitset::<Private>(futex, count, -1)) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::Duration;
+    use error::Error;
+    use super::{wait_any, wake};
+
+    #[test]
+    fn wait_any_futex() {
+        let words = Arc::new((AtomicU32::new(0), AtomicU32::new(0)));
+        let timeout = Some(Duration::from_millis(50));
+
+        let (ref shutdown, ref queue) = *words;
+        let err = wait_any(&[(shutdown, 0), (queue, 0)], timeout).unwrap_err();
+        assert_eq!(err, Error::TimedOut);
+        // stale values are reported right away
+        assert_eq!(wait_any(&[(shutdown, 0), (queue, 1)], None).unwrap(), 1);
+
+        let words2 = words.clone();
+        let waiter = thread::spawn(move || {
+            let (ref shutdown, ref queue) = *words2;
+            wait_any(&[(shutdown, 0), (queue, 0)], None).unwrap()
+        });
+        thread::sleep(Duration::from_millis(50));
+        queue.store(1, Ordering::Release);
+        wake(queue, 1);
+        assert_eq!(waiter.join().unwrap(), 1);
+    }
+}